@@ -0,0 +1,137 @@
+//! User configuration, read from `~/.config/upyremote/config.toml` and then
+//! `./upyremote.toml`. Entries of the local file override the global ones.
+//!
+//! ```toml
+//! [aliases]
+//! sensor-node-3 = "id:E6614C311B7A8B2C"
+//! bench = "10c4:ea60"
+//!
+//! [profiles.esp32]
+//! port = "sensor-node-3"
+//! baud = 115200
+//! mode = "repl"
+//! timeout = 30
+//! chunk_size = 128
+//! reset = "machine"
+//! ```
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+use crate::transport::Baud;
+
+const CONFIG_FILE: &str = "config.toml";
+const LOCAL_CONFIG_FILE: &str = "upyremote.toml";
+const ENV_PROFILE_VAR: &str = "UPYREMOTE_PROFILE";
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    /// Friendly names for ports; the value is any port specification
+    #[serde(default)]
+    pub aliases: BTreeMap<String, String>,
+    /// Named sets of connection settings
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// Mode assumed when the device's prompt can't be recognised
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProfileMode {
+    Repl,
+    Upyos,
+}
+
+/// How `reset` restarts the board when `--hard` is not given
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResetMethod {
+    /// Ctrl-D soft reset
+    Soft,
+    /// DTR/RTS toggle (serial only)
+    Hard,
+    /// `machine.reset()` through the raw REPL
+    Machine,
+}
+
+/// Settings for one board; every field is optional
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Port specification, as accepted by `-p`
+    pub port: Option<String>,
+    /// Baud rate, or "auto"
+    pub baud: Option<Baud>,
+    pub mode: Option<ProfileMode>,
+    /// Default timeout in seconds for exec, run and send
+    pub timeout: Option<u64>,
+    /// Bytes of file data per raw REPL command in transfers
+    pub chunk_size: Option<usize>,
+    pub reset: Option<ResetMethod>,
+}
+
+/// Global configuration file, honouring `XDG_CONFIG_HOME`
+fn global_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::home_dir().map(|home| home.join(".config")))?;
+    Some(base.join("upyremote").join(CONFIG_FILE))
+}
+
+impl Config {
+    /// Loads and merges the global and local configuration files.
+    /// Missing files are skipped; invalid ones are an error.
+    pub fn load() -> Result<Self> {
+        let mut config = Config::default();
+        let paths = global_path()
+            .into_iter()
+            .chain(std::iter::once(PathBuf::from(LOCAL_CONFIG_FILE)));
+
+        for path in paths {
+            if !path.is_file() {
+                continue;
+            }
+            let text = fs::read_to_string(&path)
+                .with_context(|| format!("Could not read {}", path.display()))?;
+            let file: Config = toml::from_str(&text)
+                .with_context(|| format!("Invalid configuration in {}", path.display()))?;
+            config.merge(file);
+        }
+        Ok(config)
+    }
+
+    fn merge(&mut self, other: Config) {
+        self.aliases.extend(other.aliases);
+        self.profiles.extend(other.profiles);
+    }
+
+    /// Profile selected by `--profile`, then `UPYREMOTE_PROFILE`.
+    /// Without either, all settings are left at their defaults.
+    pub fn profile(&self, name: Option<String>) -> Result<Profile> {
+        let Some(name) = name.or_else(|| std::env::var(ENV_PROFILE_VAR).ok()) else {
+            return Ok(Profile::default());
+        };
+
+        match self.profiles.get(&name) {
+            Some(profile) => {
+                if profile.chunk_size == Some(0) {
+                    anyhow::bail!("Profile '{}': chunk_size must be greater than 0", name);
+                }
+                Ok(profile.clone())
+            }
+            None if self.profiles.is_empty() => {
+                anyhow::bail!("Unknown profile '{}' (no profiles are configured)", name)
+            }
+            None => anyhow::bail!(
+                "Unknown profile '{}' (available: {})",
+                name,
+                self.profiles
+                    .keys()
+                    .map(String::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! Shell-style wildcards for selecting files in recursive transfers.
+//!
+//! `*` and `?` match within one path component, `**` also across `/`,
+//! and `[abc]`, `[a-z]` and `[!abc]` match one character of a set.
+
+/// Include and exclude patterns for a directory walk. Patterns containing a
+/// `/` are matched against the path relative to the top directory, others
+/// against the file or directory name alone.
+pub struct PathFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl PathFilter {
+    pub fn new(include: Vec<String>, exclude: Vec<String>) -> Self {
+        PathFilter { include, exclude }
+    }
+
+    /// Whether the file or directory at `path` is excluded, together with
+    /// everything below it
+    pub fn excludes(&self, path: &str) -> bool {
+        self.exclude.iter().any(|p| matches_path(p, path))
+    }
+
+    /// Whether `path` is excluded itself or lies below an excluded directory
+    pub fn excludes_tree(&self, path: &str) -> bool {
+        path.match_indices('/')
+            .map(|(i, _)| &path[..i])
+            .chain([path])
+            .any(|p| self.excludes(p))
+    }
+
+    /// Whether only files matching an include pattern are selected
+    pub fn has_includes(&self) -> bool {
+        !self.include.is_empty()
+    }
+
+    /// Whether the file at `path` is selected: not excluded and, when there
+    /// are include patterns, matching one of them
+    pub fn includes_file(&self, path: &str) -> bool {
+        !self.excludes(path)
+            && (self.include.is_empty() || self.include.iter().any(|p| matches_path(p, path)))
+    }
+}
+
+fn matches_path(pattern: &str, path: &str) -> bool {
+    if pattern.contains('/') {
+        matches(pattern.trim_start_matches('/'), path)
+    } else {
+        matches(pattern, path.rsplit('/').next().unwrap_or(path))
+    }
+}
+
+/// Whether `text` matches the whole of `pattern`
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    match_from(&pattern, &text)
+}
+
+fn match_from(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') => {
+            let double = pattern.get(1) == Some(&'*');
+            let rest = if double { &pattern[2..] } else { &pattern[1..] };
+            // `**/` also matches no directory at all
+            if double && rest.first() == Some(&'/') && match_from(&rest[1..], text) {
+                return true;
+            }
+            (0..=text.len())
+                .take_while(|&i| double || i == 0 || text[i - 1] != '/')
+                .any(|i| match_from(rest, &text[i..]))
+        }
+        Some('?') => {
+            text.first().is_some_and(|&c| c != '/') && match_from(&pattern[1..], &text[1..])
+        }
+        Some('[') => match parse_class(&pattern[1..]) {
+            Some((class, len)) => {
+                text.first().is_some_and(|&c| c != '/' && class.contains(c))
+                    && match_from(&pattern[1 + len..], &text[1..])
+            }
+            // No closing bracket: a literal '['
+            None => text.first() == Some(&'[') && match_from(&pattern[1..], &text[1..]),
+        },
+        Some(&c) => text.first() == Some(&c) && match_from(&pattern[1..], &text[1..]),
+    }
+}
+
+struct CharClass {
+    negated: bool,
+    ranges: Vec<(char, char)>,
+}
+
+impl CharClass {
+    fn contains(&self, c: char) -> bool {
+        self.ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != self.negated
+    }
+}
+
+/// Parses the set after a `[`. Returns it with the number of pattern
+/// characters it used, including the closing `]`.
+fn parse_class(pattern: &[char]) -> Option<(CharClass, usize)> {
+    let negated = matches!(pattern.first(), Some('!' | '^'));
+    let mut i = usize::from(negated);
+    let mut ranges = Vec::new();
+    // A ']' right after the opening bracket is part of the set
+    let mut first = true;
+    loop {
+        let c = *pattern.get(i)?;
+        if c == ']' && !first {
+            return Some((CharClass { negated, ranges }, i + 1));
+        }
+        first = false;
+        if pattern.get(i + 1) == Some(&'-') && pattern.get(i + 2).is_some_and(|&e| e != ']') {
+            ranges.push((c, pattern[i + 2]));
+            i += 3;
+        } else {
+            ranges.push((c, c));
+            i += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn excluded_directories_cover_their_contents() {
+        let filter = PathFilter::new(vec![], vec!["__pycache__".into(), "lib/tmp".into()]);
+        assert!(filter.excludes_tree("lib/__pycache__/x.mpy"));
+        assert!(filter.excludes_tree("__pycache__"));
+        assert!(filter.excludes_tree("lib/tmp/a/b.txt"));
+        assert!(!filter.excludes_tree("lib/main.py"));
+        assert!(!filter.excludes_tree("tmp/b.txt"));
+        // The file name alone isn't excluded, only its directory
+        assert!(!filter.excludes("lib/__pycache__/x.mpy"));
+    }
+}
@@ -4,16 +4,57 @@ use crossterm::{
     event::{self, Event, KeyCode, KeyModifiers},
     terminal::{disable_raw_mode, enable_raw_mode},
 };
-use serialport::{DataBits, FlowControl, Parity, StopBits};
 use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsString,
     io::{self, Read, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::{
+        Once,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
     thread,
     time::Duration,
 };
 
+mod config;
+mod glob;
+mod mount;
+mod ports;
+mod progress;
+mod quote;
+mod script;
+mod sha256;
+mod simulator;
+mod tcp;
+mod transport;
+mod watch;
+mod webrepl;
+
+use config::{Config, Profile, ProfileMode, ResetMethod};
+use glob::PathFilter;
+use mount::{MOUNT_POINT, MOUNT_REQUEST, MountServer};
+use progress::Progress;
+use quote::{py_list, py_str, shell_arg};
+use simulator::SimMode;
+use transport::{Baud, Transport};
+use watch::Watcher;
+
 const DEFAULT_PORT: &str = "/dev/ttyACM0";
 const ENV_PORT_VAR: &str = "UPYREMOTE_PORT";
+const DEFAULT_BAUD: u32 = 115200;
+/// Bytes of file data sent to or read from the device per raw REPL command
+const DEFAULT_CHUNK_SIZE: usize = 1024;
+/// Bytes per raw REPL write when raw-paste mode is unavailable
+const RAW_WRITE_CHUNK: usize = 256;
+/// Files used on upyOS devices for binary-safe transfers
+const UPYOS_HELPER_PATH: &str = "/.upyremote.py";
+const UPYOS_PART_PATH: &str = "/.upyremote.b64";
+/// Bytes of base64 text per `fileup`, well below its 20 KB buffer
+const UPYOS_PART_SIZE: usize = 16 * 1024;
+const UPYOS_BEGIN_MARKER: &str = "UPYREMOTE-BEGIN";
+const UPYOS_END_MARKER: &str = "UPYREMOTE-END";
+const RAW_REPL_BANNER: &[u8] = b"raw REPL; CTRL-B to exit\r\n>";
 
 #[derive(Parser)]
 #[command(name = "upyremote")]
@@ -22,13 +63,46 @@ const ENV_PORT_VAR: &str = "UPYREMOTE_PORT";
     long_about = "Universal CLI tool for MicroPython REPL and upyOS remote management
 
 Port Configuration:
-  Default port: /dev/ttyACM0
+  Default port: /dev/ttyACM0, or 'auto' when it does not exist
   Environment variable: UPYREMOTE_PORT
-  Priority: -p argument > UPYREMOTE_PORT env var > default"
+  Priority: -p argument > profile > UPYREMOTE_PORT env var > default
+  '-p auto' picks the first port with a known MicroPython USB ID
+  '-p id:<serial>' and '-p <vid>:<pid>' select a board by its USB details
+  Aliases for any port can be defined in ~/.config/upyremote/config.toml
+  or ./upyremote.toml:  [aliases]  sensor-node-3 = \"id:E6614C311B7A8B2C\"
+
+Profiles:
+  [profiles.<name>] in the same files sets port, baud, mode, timeout,
+  chunk_size and reset. Select with --profile or UPYREMOTE_PROFILE."
 )]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Baud rate for serial ports, or 'auto' to detect it [default: 115200]
+    #[arg(short, long, global = true)]
+    baud: Option<Baud>,
+    /// Bytes of file data per raw REPL command in transfers [default: 1024]
+    #[arg(long, global = true, value_parser = clap::value_parser!(u64).range(1..))]
+    chunk_size: Option<u64>,
+    /// Configuration profile to use [env: UPYREMOTE_PROFILE]
+    #[arg(long, global = true)]
+    profile: Option<String>,
+    /// Don't show transfer progress
+    #[arg(short, long, global = true, conflicts_with = "progress")]
+    quiet: bool,
+    /// Show transfer progress even when stdout is not a terminal
+    #[arg(long, global = true)]
+    progress: bool,
+}
+
+// A command after a `+`. It uses the options given before the first
+// command and, without `-p`, the device of the command before it.
+#[derive(Parser)]
+#[command(name = "upyremote", no_binary_name = true)]
+#[command(about = "Command chained with '+', run on the same connection")]
+struct ChainedCommand {
+    #[command(subcommand)]
+    command: Commands,
 }
 
 #[derive(Subcommand)]
@@ -38,9 +112,6 @@ enum Commands {
         /// Serial port [default: /dev/ttyACM0, env: UPYREMOTE_PORT]
         #[arg(short, long)]
         port: Option<String>,
-        /// Baud rate
-        #[arg(short, long, default_value = "115200")]
-        baud: u32,
     },
     /// List files on device
     Ls {
@@ -50,26 +121,156 @@ enum Commands {
         /// Directory to list
         #[arg(default_value = "/")]
         path: String,
+        /// Show type, size and modification time
+        #[arg(short, long)]
+        long: bool,
+        /// List subdirectories too
+        #[arg(short = 'R', long)]
+        recursive: bool,
+    },
+    /// Show total, used and free space of the device's filesystems
+    Df {
+        /// Serial port [default: /dev/ttyACM0, env: UPYREMOTE_PORT]
+        #[arg(short, long)]
+        port: Option<String>,
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Create directories on device
+    Mkdir {
+        /// Serial port [default: /dev/ttyACM0, env: UPYREMOTE_PORT]
+        // No -p here: that is --parents
+        #[arg(long)]
+        port: Option<String>,
+        /// Directories to create
+        #[arg(required = true)]
+        paths: Vec<String>,
+        /// Create parent directories as needed; existing ones are fine
+        #[arg(short, long)]
+        parents: bool,
+    },
+    /// Remove files on device
+    Rm {
+        /// Serial port [default: /dev/ttyACM0, env: UPYREMOTE_PORT]
+        #[arg(short, long)]
+        port: Option<String>,
+        /// Files to remove
+        #[arg(required = true)]
+        paths: Vec<String>,
+        /// Remove directories and everything below them
+        #[arg(short, long)]
+        recursive: bool,
+    },
+    /// Remove empty directories on device
+    Rmdir {
+        /// Serial port [default: /dev/ttyACM0, env: UPYREMOTE_PORT]
+        #[arg(short, long)]
+        port: Option<String>,
+        /// Directories to remove
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+    /// Move or rename a file or directory on device
+    Mv {
+        /// Serial port [default: /dev/ttyACM0, env: UPYREMOTE_PORT]
+        #[arg(short, long)]
+        port: Option<String>,
+        /// Path to move
+        source: String,
+        /// New path, or directory to move into
+        dest: String,
+    },
+    /// Copy a file on device
+    Cp {
+        /// Serial port [default: /dev/ttyACM0, env: UPYREMOTE_PORT]
+        #[arg(short, long)]
+        port: Option<String>,
+        /// File to copy
+        source: String,
+        /// New path, or directory to copy into
+        dest: String,
+    },
+    /// Create empty files on device
+    Touch {
+        /// Serial port [default: /dev/ttyACM0, env: UPYREMOTE_PORT]
+        #[arg(short, long)]
+        port: Option<String>,
+        /// Files to create
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+    /// Print files on device
+    Cat {
+        /// Serial port [default: /dev/ttyACM0, env: UPYREMOTE_PORT]
+        #[arg(short, long)]
+        port: Option<String>,
+        /// Files to print
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+    /// Show a directory on device and everything below it as a tree
+    Tree {
+        /// Serial port [default: /dev/ttyACM0, env: UPYREMOTE_PORT]
+        #[arg(short, long)]
+        port: Option<String>,
+        /// Directory to show
+        #[arg(default_value = "/")]
+        path: String,
     },
     /// Upload a file to device
     Put {
         /// Serial port [default: /dev/ttyACM0, env: UPYREMOTE_PORT]
         #[arg(short, long)]
         port: Option<String>,
-        /// Local file
+        /// Local file, or directory with -r
         source: PathBuf,
         /// Destination on device (optional)
         dest: Option<String>,
+        /// Upload a directory and everything below it
+        #[arg(short, long)]
+        recursive: bool,
+        /// Only upload files matching this pattern (repeatable)
+        #[arg(long, value_name = "GLOB", requires = "recursive")]
+        include: Vec<String>,
+        /// Skip files and directories matching this pattern (repeatable)
+        #[arg(long, value_name = "GLOB", requires = "recursive")]
+        exclude: Vec<String>,
     },
     /// Download a file from device
     Get {
         /// Serial port [default: /dev/ttyACM0, env: UPYREMOTE_PORT]
         #[arg(short, long)]
         port: Option<String>,
-        /// File on device
+        /// File on device, or directory with -r
         source: String,
         /// Local destination (optional)
         dest: Option<PathBuf>,
+        /// Download a directory and everything below it
+        #[arg(short, long)]
+        recursive: bool,
+    },
+    /// Upload new and changed files so a device directory matches a local one
+    Sync {
+        /// Serial port [default: /dev/ttyACM0, env: UPYREMOTE_PORT]
+        #[arg(short, long)]
+        port: Option<String>,
+        /// Local directory
+        source: PathBuf,
+        /// Directory on device [default: /]
+        dest: Option<String>,
+        /// Delete remote files that don't exist locally
+        #[arg(long)]
+        delete: bool,
+        /// Print what would be done without changing anything
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        /// Only sync files matching this pattern (repeatable)
+        #[arg(long, value_name = "GLOB")]
+        include: Vec<String>,
+        /// Skip files and directories matching this pattern (repeatable)
+        #[arg(long, value_name = "GLOB")]
+        exclude: Vec<String>,
     },
     /// Execute a command on device
     Exec {
@@ -78,16 +279,30 @@ enum Commands {
         port: Option<String>,
         /// Command to execute
         command: String,
+        /// Timeout in seconds (if not specified, waits until the code finishes)
+        #[arg(short, long)]
+        timeout: Option<u64>,
     },
     /// Reset device
     Reset {
         /// Serial port [default: /dev/ttyACM0, env: UPYREMOTE_PORT]
         #[arg(short, long)]
         port: Option<String>,
-        /// Hard reset (complete reset)
+        /// Hard reset (complete reset); otherwise the profile's reset method
         #[arg(short = 'H', long)]
         hard: bool,
     },
+    /// Run a file of upyremote commands, one per line, on one connection
+    Script {
+        /// Serial port for commands without -p [default: /dev/ttyACM0, env: UPYREMOTE_PORT]
+        #[arg(short, long)]
+        port: Option<String>,
+        /// Script file
+        file: PathBuf,
+        /// Set a variable, overriding `set` lines and the environment
+        #[arg(short, long = "set", value_name = "NAME=VALUE")]
+        set: Vec<String>,
+    },
     /// Run a Python file on device
     Run {
         /// Serial port [default: /dev/ttyACM0, env: UPYREMOTE_PORT]
@@ -95,6 +310,41 @@ enum Commands {
         port: Option<String>,
         /// File to run
         file: PathBuf,
+        /// Timeout in seconds (if not specified, waits until the script finishes)
+        #[arg(short, long)]
+        timeout: Option<u64>,
+    },
+    /// Mount a local directory on the device, then open the REPL or run a script from it
+    Mount {
+        /// Serial port [default: /dev/ttyACM0, env: UPYREMOTE_PORT]
+        #[arg(short, long)]
+        port: Option<String>,
+        /// Local directory, mounted at /remote and made the current directory
+        dir: PathBuf,
+        /// Script in the directory to run instead of opening the REPL
+        script: Option<String>,
+        /// Timeout in seconds for the script (if not specified, waits until it finishes)
+        #[arg(short, long)]
+        timeout: Option<u64>,
+    },
+    /// Upload files of a local directory when they change, then restart the code
+    Watch {
+        /// Serial port [default: /dev/ttyACM0, env: UPYREMOTE_PORT]
+        #[arg(short, long)]
+        port: Option<String>,
+        /// Local directory
+        source: PathBuf,
+        /// Directory on device [default: /]
+        dest: Option<String>,
+        /// File in the directory to run after each upload, instead of a soft reset
+        #[arg(long, value_name = "FILE")]
+        run: Option<String>,
+        /// Only watch files matching this pattern (repeatable)
+        #[arg(long, value_name = "GLOB")]
+        include: Vec<String>,
+        /// Skip files and directories matching this pattern (repeatable)
+        #[arg(long, value_name = "GLOB")]
+        exclude: Vec<String>,
     },
     /// Send commands to device and display execution results
     Send {
@@ -107,15 +357,48 @@ enum Commands {
         #[arg(short, long)]
         timeout: Option<u64>,
     },
+    /// List serial ports and the USB boards attached to them
+    Devices,
+    /// Run a simulated device, served over TCP (needs python3)
+    Simulate {
+        /// Device behaviour to simulate
+        #[arg(short, long, value_enum, default_value = "repl")]
+        mode: SimMode,
+        /// Host directory holding the device filesystem
+        #[arg(short, long, default_value = ".")]
+        root: PathBuf,
+        /// Address to listen on
+        #[arg(short, long, default_value = "127.0.0.1:2323")]
+        listen: String,
+    },
 }
 
 /// Resolves the port to use with priority:
 /// 1. Explicit port argument
-/// 2. UPYREMOTE_PORT environment variable
-/// 3. Default /dev/ttyACM0
-fn resolve_port(port: Option<String>) -> String {
-    port.or_else(|| std::env::var(ENV_PORT_VAR).ok())
-        .unwrap_or_else(|| DEFAULT_PORT.to_string())
+/// 2. Port of the selected profile
+/// 3. UPYREMOTE_PORT environment variable
+/// 4. Default /dev/ttyACM0, or auto-discovery when it does not exist
+///
+/// Aliases from the configuration file are expanded first. `auto`,
+/// `id:<serial>` and `vid:pid` are then looked up among the USB ports.
+fn resolve_port(port: Option<String>, config: &Config, profile: &Profile) -> Result<String> {
+    let port = port
+        .or_else(|| profile.port.clone())
+        .or_else(|| std::env::var(ENV_PORT_VAR).ok())
+        .unwrap_or_else(|| {
+            if Path::new(DEFAULT_PORT).exists() {
+                DEFAULT_PORT.to_string()
+            } else {
+                ports::AUTO_PORT.to_string()
+            }
+        });
+
+    let spec = config.aliases.get(&port).cloned().unwrap_or(port);
+    let resolved = ports::resolve(&spec)?;
+    if resolved != spec {
+        println!("[INFO] Using port {} ({})", resolved, spec);
+    }
+    Ok(resolved)
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -143,34 +426,149 @@ impl DeviceMode {
     }
 }
 
+/// Response of the raw REPL to a piece of code
+#[derive(Debug)]
+struct ExecResult {
+    /// Normal output of the code
+    stdout: String,
+    /// Error section of the response, where MicroPython reports exceptions
+    stderr: String,
+    /// Traceback of the exception raised by the code, if any
+    traceback: Option<String>,
+}
+
+impl ExecResult {
+    /// Builds the result from the two sections of a raw REPL response
+    /// (`stdout \x04 stderr \x04 >`)
+    fn from_sections(stdout: &[u8], stderr: &[u8]) -> Self {
+        let stdout = String::from_utf8_lossy(stdout).to_string();
+        let stderr = String::from_utf8_lossy(stderr).to_string();
+
+        let traceback = match stderr.find("Traceback") {
+            Some(start) => Some(stderr[start..].trim().to_string()),
+            None if !stderr.trim().is_empty() => Some(stderr.trim().to_string()),
+            None => None,
+        };
+
+        ExecResult {
+            stdout,
+            stderr,
+            traceback,
+        }
+    }
+}
+
+/// File or directory on the device
+struct DirEntry {
+    path: String,
+    is_dir: bool,
+    /// Size in bytes, 0 for directories
+    size: usize,
+    /// Modification time in seconds since the Unix epoch
+    mtime: u64,
+}
+
 struct MpDevice {
-    port: Box<dyn serialport::SerialPort>,
+    port: Box<dyn Transport>,
     mode: DeviceMode,
+    /// Try raw-paste mode for code transfers; cleared once the firmware refuses it
+    raw_paste: bool,
+    /// Bytes of file data per raw REPL command in transfers
+    chunk_size: usize,
+    /// Draw progress lines during file transfers
+    show_progress: bool,
+    /// Local directory served to the device by `mount`
+    mount: Option<MountServer>,
+    /// Bytes read past the start of a mount request, returned by the next read
+    pending: Vec<u8>,
 }
 
 impl MpDevice {
-    fn new(port_name: &str, baud_rate: u32) -> Result<Self> {
-        let port = serialport::new(port_name, baud_rate)
-            .data_bits(DataBits::Eight)
-            .parity(Parity::None)
-            .stop_bits(StopBits::One)
-            .flow_control(FlowControl::None)
-            .timeout(Duration::from_millis(100))
-            .open()
-            .with_context(|| format!("Could not open port {}", port_name))?;
-
-        let mut device = MpDevice {
-            port,
-            mode: DeviceMode::Unknown,
+    fn new(port_name: &str, baud: Baud, profile: &Profile) -> Result<Self> {
+        let mut device = match baud {
+            Baud::Auto if transport::is_serial(port_name) => {
+                Self::open_auto_baud(port_name, profile)?
+            }
+            // Network transports have no baud rate to detect
+            Baud::Auto => {
+                let mut device = Self::open(port_name, DEFAULT_BAUD, profile)?;
+                device.detect_mode()?;
+                device
+            }
+            Baud::Rate(rate) => {
+                let mut device = Self::open(port_name, rate, profile)?;
+                device.detect_mode()?;
+                device
+            }
         };
 
-        // Detect device mode
-        device.detect_mode()?;
+        if device.mode == DeviceMode::Unknown
+            && let Some(mode) = profile.mode
+        {
+            device.mode = match mode {
+                ProfileMode::Repl => DeviceMode::MicroPythonRepl,
+                ProfileMode::Upyos => DeviceMode::UpyOS,
+            };
+            println!(
+                "[INFO] Assuming {} mode from profile",
+                device.mode.description()
+            );
+        }
 
         Ok(device)
     }
 
+    fn open(port_name: &str, baud_rate: u32, profile: &Profile) -> Result<Self> {
+        Ok(MpDevice {
+            port: transport::open(port_name, baud_rate)?,
+            mode: DeviceMode::Unknown,
+            raw_paste: true,
+            chunk_size: profile.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE),
+            show_progress: false,
+            mount: None,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Tries the common baud rates until the device answers with a prompt
+    /// that `probe_mode` recognises
+    fn open_auto_baud(port_name: &str, profile: &Profile) -> Result<Self> {
+        for &rate in transport::AUTO_BAUD_RATES {
+            let mut device = Self::open(port_name, rate, profile)?;
+            let mode = device.probe_mode()?;
+            if mode != DeviceMode::Unknown {
+                device.mode = mode;
+                println!("[INFO] Detected baud rate: {}", rate);
+                println!("[INFO] Detected mode: {}", mode.description());
+                return Ok(device);
+            }
+        }
+
+        anyhow::bail!(
+            "Could not detect the baud rate of {}: no prompt at any of {}",
+            port_name,
+            transport::AUTO_BAUD_RATES
+                .iter()
+                .map(u32::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+
     fn detect_mode(&mut self) -> Result<()> {
+        self.mode = self.probe_mode()?;
+        if self.mode == DeviceMode::Unknown {
+            println!(
+                "[WARNING] Could not detect device mode. Some features may not work correctly."
+            );
+        } else {
+            println!("[INFO] Detected mode: {}", self.mode.description());
+        }
+        Ok(())
+    }
+
+    /// Recognises the mode from the prompt the device prints after Enter
+    fn probe_mode(&mut self) -> Result<DeviceMode> {
         // Clear input buffer
         let mut discard = [0u8; 1024];
         let _ = self.port.read(&mut discard);
@@ -199,8 +597,7 @@ impl MpDevice {
 
         // Detect mode based on prompt
         if response.contains(">>>") {
-            self.mode = DeviceMode::MicroPythonRepl;
-            println!("[INFO] Detected mode: {}", self.mode.description());
+            Ok(DeviceMode::MicroPythonRepl)
         } else if response.contains("/ $:") || response.contains("$") {
             // Try to confirm upyOS by checking version
             self.write(b"echo $SHELL\r")?;
@@ -224,22 +621,13 @@ impl MpDevice {
                 || confirm_response.contains("$SHELL")
                 || confirm_response.contains("/ $:")
             {
-                self.mode = DeviceMode::UpyOS;
-                println!("[INFO] Detected mode: {}", self.mode.description());
+                Ok(DeviceMode::UpyOS)
             } else {
-                self.mode = DeviceMode::Unknown;
-                println!(
-                    "[WARNING] Could not detect device mode. Some features may not work correctly."
-                );
+                Ok(DeviceMode::Unknown)
             }
         } else {
-            self.mode = DeviceMode::Unknown;
-            println!(
-                "[WARNING] Could not detect device mode. Some features may not work correctly."
-            );
+            Ok(DeviceMode::Unknown)
         }
-
-        Ok(())
     }
 
     fn ensure_repl_mode(&self) -> Result<()> {
@@ -276,6 +664,12 @@ impl MpDevice {
     }
 
     fn read_available(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if !self.pending.is_empty() {
+            let n = buf.len().min(self.pending.len());
+            buf[..n].copy_from_slice(&self.pending[..n]);
+            self.pending.drain(..n);
+            return Ok(n);
+        }
         match self.port.read(buf) {
             Ok(n) => Ok(n),
             Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(0),
@@ -319,21 +713,19 @@ impl MpDevice {
 
         // Ctrl-A to enter raw REPL
         self.write(&[0x01])?;
-        thread::sleep(Duration::from_millis(200));
 
-        // Read prompt
+        // Wait for the raw REPL banner, which ends at the '>' prompt
         let mut buf = vec![];
-        if self.read_until(b">>>", &mut buf, 1000)? {
-            // Verify we are in raw mode
-            let response = String::from_utf8_lossy(&buf);
-            if response.contains("raw REPL") || response.contains("CTRL-B") {
-                return Ok(());
-            }
+        if self.read_until(RAW_REPL_BANNER, &mut buf, 1000)? {
+            return Ok(());
         }
 
         // Try again
         self.write(&[0x01])?;
-        thread::sleep(Duration::from_millis(500));
+        buf.clear();
+        if !self.read_until(RAW_REPL_BANNER, &mut buf, 1000)? {
+            anyhow::bail!("Could not enter raw REPL");
+        }
         Ok(())
     }
 
@@ -344,15 +736,51 @@ impl MpDevice {
         Ok(())
     }
 
-    fn exec_command(&mut self, code: &str) -> Result<String> {
-        self.ensure_repl_mode()?;
-        self.enter_raw_repl()?;
+    /// Reads exactly `len` bytes, failing if they don't arrive within the timeout.
+    /// Never consumes more than requested, so the stream stays in sync.
+    fn read_exact_timeout(&mut self, len: usize, timeout_ms: u64) -> Result<Vec<u8>> {
+        let start = std::time::Instant::now();
+        let mut buf = vec![0u8; len];
+        let mut filled = 0;
+
+        while filled < len {
+            if start.elapsed().as_millis() > timeout_ms as u128 {
+                anyhow::bail!(
+                    "Timeout waiting for device response (got {:?})",
+                    String::from_utf8_lossy(&buf[..filled])
+                );
+            }
+            filled += self.read_available(&mut buf[filled..])?;
+        }
 
-        // Send code
-        let code_bytes = code.as_bytes();
+        Ok(buf)
+    }
+
+    /// Sends code at the raw REPL '>' prompt and starts its execution.
+    /// Uses raw-paste mode when the firmware supports it, otherwise falls back
+    /// to writing the code in fixed-size chunks.
+    fn write_raw_code(&mut self, code: &[u8]) -> Result<()> {
+        if self.raw_paste {
+            // Ctrl-E A Ctrl-A requests raw-paste mode
+            self.write(b"\x05A\x01")?;
+            let reply = self.read_exact_timeout(2, 1000)?;
+            match reply.as_slice() {
+                b"R\x01" => return self.raw_paste_write(code),
+                // Raw-paste understood but not supported by this build
+                b"R\x00" => {}
+                _ => {
+                    // Older firmware ignores the request and prints the banner again
+                    let mut buf = reply;
+                    if !self.read_until(RAW_REPL_BANNER, &mut buf, 1000)? {
+                        anyhow::bail!("Could not resync with raw REPL after raw-paste request");
+                    }
+                }
+            }
+            self.raw_paste = false;
+        }
 
         // Send in chunks
-        for chunk in code_bytes.chunks(256) {
+        for chunk in code.chunks(RAW_WRITE_CHUNK) {
             self.write(chunk)?;
             thread::sleep(Duration::from_millis(50));
         }
@@ -360,159 +788,1266 @@ impl MpDevice {
         // Ctrl-D to execute
         self.write(&[0x04])?;
 
-        // Read response
-        let mut response = vec![];
-        self.read_until(b"\x04>", &mut response, 5000)?;
+        let reply = self.read_exact_timeout(2, 1000)?;
+        if reply != b"OK" {
+            anyhow::bail!(
+                "Device did not accept code: {}",
+                String::from_utf8_lossy(&reply)
+            );
+        }
+        Ok(())
+    }
 
-        self.exit_raw_repl()?;
+    /// Raw-paste transfer: the device announces a window size and sends
+    /// Ctrl-A each time another window's worth of data may be sent.
+    fn raw_paste_write(&mut self, code: &[u8]) -> Result<()> {
+        let header = self.read_exact_timeout(2, 1000)?;
+        let window = u16::from_le_bytes([header[0], header[1]]) as usize;
+        let mut remaining = window;
+        let mut sent = 0;
+
+        while sent < code.len() {
+            // Process flow-control bytes, blocking while the window is used up
+            while remaining == 0 || self.port.bytes_to_read()? > 0 {
+                match self.read_exact_timeout(1, 5000)?[0] {
+                    0x01 => remaining += window,
+                    0x04 => {
+                        // Device wants to end the transfer early
+                        self.write(&[0x04])?;
+                        return Ok(());
+                    }
+                    other => {
+                        anyhow::bail!("Unexpected byte 0x{:02x} during raw paste", other)
+                    }
+                }
+            }
 
-        // Parse response
-        let output = String::from_utf8_lossy(&response);
+            let end = (sent + remaining).min(code.len());
+            self.write(&code[sent..end])?;
+            remaining -= end - sent;
+            sent = end;
+        }
 
-        // Look between OK and \x04 markers
-        if let Some(start) = output.find("OK") {
-            let rest = &output[start + 2..];
-            if let Some(end) = rest.find('\x04') {
-                let result = &rest[..end];
-                // Clean output
-                return Ok(result.trim().to_string());
+        // Ctrl-D ends the data; the device acknowledges with Ctrl-D before compiling
+        self.write(&[0x04])?;
+        loop {
+            match self.read_exact_timeout(1, 5000)?[0] {
+                0x04 => return Ok(()),
+                0x01 => {}
+                other => anyhow::bail!("Could not complete raw paste (got 0x{:02x})", other),
             }
         }
-
-        Ok(output.to_string())
     }
 
-    fn list_files(&mut self, path: &str) -> Result<Vec<String>> {
-        match self.mode {
-            DeviceMode::MicroPythonRepl => self.list_files_repl(path),
-            DeviceMode::UpyOS => self.list_files_upyos(path),
-            DeviceMode::Unknown => {
-                // Try REPL mode first
-                self.list_files_repl(path)
-            }
-        }
+    /// Runs code, streaming its output to stdout while it runs.
+    /// Ctrl-C is forwarded to the device to stop the code.
+    fn exec_streaming(&mut self, code: &str, timeout: Option<Duration>) -> Result<ExecResult> {
+        let _interrupts = install_interrupt_handler();
+        let mut stdout = io::stdout();
+        self.exec_with(code, timeout, Some(&mut stdout))
     }
 
-    fn list_files_repl(&mut self, path: &str) -> Result<Vec<String>> {
-        let cmd = format!(
-            r#"import os
-try:
-    files = os.listdir("{}")
-    for f in files:
-        print(f)
-except OSError as e:
-    print("Error:", e)"#,
-            path
-        );
+    fn exec_with(
+        &mut self,
+        code: &str,
+        timeout: Option<Duration>,
+        stream: Option<&mut dyn Write>,
+    ) -> Result<ExecResult> {
+        self.ensure_repl_mode()?;
+        self.enter_raw_repl()?;
+        self.write_raw_code(code.as_bytes())?;
 
-        let output = self.exec_command(&cmd)?;
-        let files: Vec<String> = output
-            .lines()
-            .map(|s| s.trim().to_string())
-            .filter(|s| !s.is_empty() && !s.starts_with("Error:"))
-            .collect();
+        let result = self.read_exec_response(timeout, stream)?;
 
-        Ok(files)
-    }
+        self.exit_raw_repl()?;
 
-    fn list_files_upyos(&mut self, path: &str) -> Result<Vec<String>> {
-        self.ensure_upyos_mode()?;
+        Ok(result)
+    }
 
-        let cmd = format!("ls -1 {}\r", path);
-        self.write(cmd.as_bytes())?;
+    /// Reads the response to code running in the raw REPL, up to the final '>'.
+    /// Output is written to `stream` as it arrives when one is given, otherwise
+    /// it is collected into the result. When the timeout expires the code is
+    /// interrupted and an error returned.
+    fn read_exec_response(
+        &mut self,
+        timeout: Option<Duration>,
+        stream: Option<&mut dyn Write>,
+    ) -> Result<ExecResult> {
+        self.read_exec_response_until(timeout, stream, &mut || false)
+    }
 
-        // Read response until prompt
-        let mut response = Vec::new();
-        let mut buf = [0u8; 1024];
+    /// `read_exec_response`, interrupting the code like Ctrl-C once `stop`
+    /// returns true. `stop` is called while waiting for output.
+    fn read_exec_response_until(
+        &mut self,
+        timeout: Option<Duration>,
+        mut stream: Option<&mut dyn Write>,
+        stop: &mut dyn FnMut() -> bool,
+    ) -> Result<ExecResult> {
         let start = std::time::Instant::now();
-        const PROMPT: &[u8] = b"$:";
+        let mut deadline = timeout;
+        let mut timed_out = false;
+        let mut stopped = false;
+        let mut interrupts = INTERRUPTS.load(Ordering::SeqCst);
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let mut markers = 0;
+        let mut buf = [0u8; 1024];
 
-        while start.elapsed().as_secs() < 5 {
-            match self.port.read(&mut buf) {
-                Ok(n) if n > 0 => {
-                    response.extend_from_slice(&buf[..n]);
-                    if response.windows(PROMPT.len()).any(|w| w == PROMPT) {
-                        break;
+        while markers < 2 {
+            // Forward local Ctrl-C presses to the device
+            let pressed = INTERRUPTS.load(Ordering::SeqCst);
+            if pressed != interrupts {
+                interrupts = pressed;
+                self.write(&[0x03])?;
+            }
+            if !stopped && stop() {
+                self.write(&[0x03])?;
+                stopped = true;
+            }
+
+            if let Some(limit) = deadline
+                && start.elapsed() > limit
+            {
+                if timed_out {
+                    anyhow::bail!("Device did not stop after timeout");
+                }
+                // Interrupt the code and give the device a moment to report it
+                self.write(&[0x03])?;
+                timed_out = true;
+                deadline = Some(limit + Duration::from_secs(2));
+            }
+
+            let n = self.read_available(&mut buf)?;
+            let (mut data, request) = if markers == 0 {
+                self.take_mount_request(&buf[..n])
+            } else {
+                (&buf[..n], false)
+            };
+            while !data.is_empty() && markers < 2 {
+                let end = data.iter().position(|&b| b == 0x04).unwrap_or(data.len());
+                let (section, rest) = data.split_at(end);
+                if markers == 0 {
+                    match stream.as_mut() {
+                        Some(out) => {
+                            out.write_all(section)?;
+                            out.flush()?;
+                        }
+                        None => stdout.extend_from_slice(section),
                     }
+                } else {
+                    stderr.extend_from_slice(section);
                 }
-                Ok(_) => {}
-                Err(_) => thread::sleep(Duration::from_millis(10)),
+                if rest.is_empty() {
+                    break;
+                }
+                markers += 1;
+                data = &rest[1..];
+            }
+
+            if request {
+                self.serve_mount_request()?;
+            }
+
+            // The response ends with the raw REPL prompt
+            if markers == 2 && !data.contains(&b'>') {
+                let mut prompt = vec![];
+                self.read_until(b">", &mut prompt, 1000)?;
             }
         }
 
-        let output = String::from_utf8_lossy(&response);
-        let files: Vec<String> = output
-            .lines()
-            .map(|s| s.trim().to_string())
-            .filter(|s| !s.is_empty() && !s.contains("$:"))
-            .collect();
+        if timed_out {
+            anyhow::bail!(
+                "Timeout: code still running after {} s, interrupted",
+                timeout.unwrap_or_default().as_secs()
+            );
+        }
 
-        Ok(files)
+        Ok(ExecResult::from_sections(&stdout, &stderr))
     }
 
-    fn put_file(&mut self, local_path: &PathBuf, remote_path: &str) -> Result<()> {
-        match self.mode {
-            DeviceMode::MicroPythonRepl => self.put_file_repl(local_path, remote_path),
-            DeviceMode::UpyOS => self.put_file_upyos(local_path, remote_path),
-            DeviceMode::Unknown => {
-                // Try REPL mode first
-                self.put_file_repl(local_path, remote_path)
+    /// Runs code in an already entered raw REPL and returns its output,
+    /// turning a device-side exception into an error. Variables defined by
+    /// earlier code are still available.
+    fn raw_exec_checked(&mut self, code: &str) -> Result<String> {
+        self.write_raw_code(code.as_bytes())?;
+        let result = self.read_exec_response(Some(Duration::from_secs(5)), None)?;
+        if let Some(traceback) = result.traceback {
+            anyhow::bail!("Device raised an exception:\n{}", traceback);
+        }
+        Ok(result.stdout)
+    }
+
+    /// Splits device output at the start of a request from the mounted
+    /// directory. Returns the output before it and whether there is a
+    /// request; the bytes after it are kept for the next read.
+    fn take_mount_request<'a>(&mut self, data: &'a [u8]) -> (&'a [u8], bool) {
+        if self.mount.is_none() {
+            return (data, false);
+        }
+        // Requests are only made while code runs, before the raw REPL's end marker
+        match data.iter().position(|&b| b == MOUNT_REQUEST || b == 0x04) {
+            Some(i) if data[i] == MOUNT_REQUEST => {
+                self.pending.splice(0..0, data[i + 1..].iter().copied());
+                (&data[..i], true)
             }
+            _ => (data, false),
         }
     }
 
-    fn put_file_repl(&mut self, local_path: &PathBuf, remote_path: &str) -> Result<()> {
-        let content = std::fs::read(local_path)
-            .with_context(|| format!("Could not read {}", local_path.display()))?;
+    /// Reads one request of the mounted directory, after its start byte,
+    /// and sends the reply
+    fn serve_mount_request(&mut self) -> Result<()> {
+        let Some(mut server) = self.mount.take() else {
+            return Ok(());
+        };
+        let result = (|| {
+            let command = self.read_exact_timeout(1, 2000)?[0];
+            server.handle(command, &mut |n| self.read_exact_timeout(n, 2000))
+        })();
+        self.mount = Some(server);
+        let reply = result.context("Invalid request from the mounted directory")?;
+        self.write(&reply)
+    }
 
-        // Check size
-        if content.len() > 10000 {
-            println!(
-                "Large file ({} bytes), uploading in parts...",
-                content.len()
-            );
+    /// Device output for the terminal, with the requests of the mounted
+    /// directory answered in between
+    fn show_output(&mut self, data: &[u8], out: &mut dyn Write) -> Result<()> {
+        let (output, request) = self.take_mount_request(data);
+        out.write_all(output)?;
+        out.flush()?;
+        if request {
+            self.serve_mount_request()?;
         }
+        Ok(())
+    }
 
-        // Encode content in base64
-        let b64_content = base64_encode(&content);
+    /// Mounts `local_dir` on the device at `MOUNT_POINT` and makes it the
+    /// current directory, then runs `script` from it or opens the REPL.
+    /// The directory is served until the script ends or the REPL is left,
+    /// and unmounted again however that happens.
+    fn mount(
+        &mut self,
+        local_dir: &Path,
+        script: Option<&str>,
+        timeout: Option<Duration>,
+    ) -> Result<Option<ExecResult>> {
+        self.ensure_repl_mode()?;
+        if !local_dir.is_dir() {
+            anyhow::bail!("'{}' is not a directory", local_dir.display());
+        }
+        self.mount = Some(MountServer::new(local_dir));
+        let result = self.serve_mount(local_dir, script, timeout);
+        // Later commands must not take their output for requests
+        self.mount = None;
+        self.pending.clear();
+        let unmounted = self.unmount();
+        let result = result?;
+        unmounted?;
+        Ok(result)
+    }
 
-        let cmd = format!(
-            r#"import ubinascii
-data = ubinascii.a2b_base64('{}')
-with open('{}', 'wb') as f:
-    f.write(data)
-print('OK')"#,
-            b64_content, remote_path
+    fn serve_mount(
+        &mut self,
+        local_dir: &Path,
+        script: Option<&str>,
+        timeout: Option<Duration>,
+    ) -> Result<Option<ExecResult>> {
+        self.enter_raw_repl()?;
+        self.raw_exec_checked(&mount::shim())
+            .context("Could not mount the directory on the device")?;
+        eprintln!(
+            "[INFO] Mounted '{}' at {}",
+            local_dir.display(),
+            MOUNT_POINT
         );
 
-        let result = self.exec_command(&cmd)?;
-
-        if result.contains("OK") || result.is_empty() || result.lines().any(|l| l.contains("OK")) {
-            println!(
-                "✓ File '{}' uploaded to '{}' ({} bytes)",
-                local_path.display(),
-                remote_path,
-                content.len()
-            );
-            Ok(())
-        } else {
-            anyhow::bail!("Error uploading file: {}", result)
+        match script {
+            Some(script) => {
+                let _interrupts = install_interrupt_handler();
+                let code = format!("exec(open({}).read())", py_str(script));
+                self.write_raw_code(code.as_bytes())?;
+                let result = self.read_exec_response(timeout, Some(&mut io::stdout()))?;
+                Ok(Some(result))
+            }
+            None => {
+                self.exit_raw_repl()?;
+                self.run_repl()?;
+                Ok(None)
+            }
         }
     }
 
-    fn put_file_upyos(&mut self, local_path: &PathBuf, remote_path: &str) -> Result<()> {
-        self.ensure_upyos_mode()?;
+    /// Leaves the mounted directory on the device, interrupting code that
+    /// still runs. Not being mounted, e.g. after a reset, is no error.
+    fn unmount(&mut self) -> Result<()> {
+        self.enter_raw_repl()?;
+        let _ = self.raw_exec_checked(&mount::unmount());
+        self.exit_raw_repl()
+    }
 
-        let content = std::fs::read_to_string(local_path)
-            .with_context(|| format!("Could not read {}", local_path.display()))?;
+    /// Soft-resets the interpreter from the raw REPL, which stays active, so
+    /// that code runs without the variables and modules of earlier runs
+    fn raw_soft_reset(&mut self) -> Result<()> {
+        self.write(&[0x04])?;
+        let mut buf = vec![];
+        if !self.read_until(RAW_REPL_BANNER, &mut buf, 3000)? {
+            anyhow::bail!("Device did not return to the raw REPL after a soft reset");
+        }
+        Ok(())
+    }
 
-        // Check file size (upyOS fileup has 20KB limit)
-        if content.len() > 20000 {
-            anyhow::bail!("File too large for upyOS fileup (max 20KB)");
+    /// Uploads the files of `local_dir` to `remote_dir` whenever they change,
+    /// then runs `entry` from there again after a soft reset, or just
+    /// soft-resets the board without one. A change interrupts code that still
+    /// runs. Ctrl-C stops `entry`, and stops watching when no code runs.
+    fn watch(
+        &mut self,
+        local_dir: &Path,
+        remote_dir: &str,
+        filter: &PathFilter,
+        entry: Option<&str>,
+    ) -> Result<()> {
+        if entry.is_some() {
+            self.ensure_repl_mode()?;
         }
+        // Taken before the first sync, so files saved during it are uploaded again
+        let mut watcher = Watcher::new(local_dir, filter)?;
+        self.sync(local_dir, remote_dir, filter, false, false)?;
+        let _interrupts = install_interrupt_handler();
+        eprintln!(
+            "[INFO] Watching '{}', press Ctrl+C to stop",
+            local_dir.display()
+        );
 
+        loop {
+            self.discard_output()?;
+            let changed = match entry {
+                Some(entry) => self.run_watched(remote_dir, entry, &mut watcher)?,
+                None => {
+                    if self.mode == DeviceMode::MicroPythonRepl {
+                        self.soft_reset()?;
+                    }
+                    false
+                }
+            };
+            if !changed && !self.show_output_until_change(&mut watcher)? {
+                eprintln!("[INFO] Stopped watching '{}'", local_dir.display());
+                return Ok(());
+            }
+
+            let files = watcher.take_changes()?;
+            let mut dirs: Vec<String> = Vec::new();
+            for file in &files {
+                if let Some((dir, _)) = file.rsplit_once('/') {
+                    for dir in remote_ancestors(&join_remote(remote_dir, dir)) {
+                        if !dirs.contains(&dir) {
+                            dirs.push(dir);
+                        }
+                    }
+                }
+            }
+            if !dirs.is_empty() {
+                self.in_session(|device, _| device.make_remote_dirs(&dirs))?;
+            }
+            for file in &files {
+                // A file that can't be uploaded is tried again on its next change
+                if let Err(e) = self.put_file(&local_dir.join(file), &join_remote(remote_dir, file))
+                {
+                    eprintln!("[WARNING] {:#}", e);
+                }
+            }
+        }
+    }
+
+    /// Runs `entry` in `dir` after a soft reset, with `dir` as the current
+    /// directory so its modules can be imported, and shows the output until
+    /// the code ends or a watched file changes. Returns whether a file changed.
+    fn run_watched(&mut self, dir: &str, entry: &str, watcher: &mut Watcher) -> Result<bool> {
+        self.enter_raw_repl()?;
+        self.raw_soft_reset()?;
+        let code = format!(
+            "__import__('os').chdir({})\nexec(open({}).read())",
+            py_str(dir),
+            py_str(entry)
+        );
+        self.write_raw_code(code.as_bytes())?;
+        let mut changed = false;
+        let result = self.read_exec_response_until(None, Some(&mut io::stdout()), &mut || {
+            changed = watcher.poll();
+            changed
+        })?;
+        self.exit_raw_repl()?;
+        // The friendly REPL's banner isn't output of the code
+        self.discard_output()?;
+
+        if !changed {
+            // The traceback of an interruption for a change is of no interest
+            if result.traceback.is_some() {
+                eprintln!("{}", result.stderr.trim_end());
+            }
+            eprintln!("[INFO] '{}' ended, waiting for changes", entry);
+        }
+        Ok(changed)
+    }
+
+    /// Drops device output that has arrived but not been read
+    fn discard_output(&mut self) -> Result<()> {
+        let mut discard = [0u8; 1024];
+        while self.read_available(&mut discard)? > 0 {}
+        Ok(())
+    }
+
+    /// Shows device output until a watched file changes. Returns false
+    /// instead when Ctrl-C is pressed.
+    fn show_output_until_change(&mut self, watcher: &mut Watcher) -> Result<bool> {
+        let interrupts = INTERRUPTS.load(Ordering::SeqCst);
+        let mut stdout = io::stdout();
+        let mut buf = [0u8; 1024];
+        while !watcher.poll() {
+            if INTERRUPTS.load(Ordering::SeqCst) != interrupts {
+                return Ok(false);
+            }
+            let n = self.read_available(&mut buf)?;
+            stdout.write_all(&buf[..n])?;
+            stdout.flush()?;
+        }
+        Ok(true)
+    }
+
+    fn put_file(&mut self, local_path: &PathBuf, remote_path: &str) -> Result<()> {
+        // Transports with their own file-transfer protocol (WebREPL) don't need the REPL
+        let content = std::fs::read(local_path)
+            .with_context(|| format!("Could not read {}", local_path.display()))?;
+        let mut progress = Progress::new(
+            &format!("Uploading {}", remote_path),
+            Some(content.len()),
+            self.show_progress,
+        );
+        if self.port.has_file_transfer() {
+            // The transfer itself bypasses the REPL, but the space check needs it
+            self.in_session(|device, _| device.warn_if_short_of_space(remote_path, content.len()))?;
+        }
+        if let Some(result) = self.port.put_file(remote_path, &content, &mut progress) {
+            result.with_context(|| format!("Error uploading file '{}'", remote_path))?;
+            progress.finish();
+            println!(
+                "✓ File '{}' uploaded to '{}' ({} bytes)",
+                local_path.display(),
+                remote_path,
+                content.len()
+            );
+            return Ok(());
+        }
+
+        match self.mode {
+            DeviceMode::MicroPythonRepl => {
+                self.put_file_repl(&content, local_path, remote_path, &mut progress)
+            }
+            DeviceMode::UpyOS => {
+                self.put_file_upyos(&content, local_path, remote_path, &mut progress)
+            }
+            DeviceMode::Unknown => {
+                // Try REPL mode first
+                self.put_file_repl(&content, local_path, remote_path, &mut progress)
+            }
+        }
+    }
+
+    /// Uploads through the raw REPL in chunks appended to an open file, so the
+    /// device never holds more than one chunk of the file in memory
+    fn put_file_repl(
+        &mut self,
+        content: &[u8],
+        local_path: &Path,
+        remote_path: &str,
+        progress: &mut Progress,
+    ) -> Result<()> {
+        self.ensure_repl_mode()?;
+        self.enter_raw_repl()?;
+
+        let result = self
+            .warn_if_short_of_space(remote_path, content.len())
+            .and_then(|_| self.write_remote_file(content, remote_path, progress));
+        if result.is_err() {
+            // Don't leave the file open on the device
+            let _ = self.raw_exec_checked("f.close()");
+        }
+        self.exit_raw_repl()?;
+        result.with_context(|| format!("Error uploading file '{}'", remote_path))?;
+        progress.finish();
+
+        println!(
+            "✓ File '{}' uploaded to '{}' ({} bytes)",
+            local_path.display(),
+            remote_path,
+            content.len()
+        );
+        Ok(())
+    }
+
+    /// Writes `content` to a file on the device and checks its final size.
+    /// Must be called in the raw REPL.
+    fn write_remote_file(
+        &mut self,
+        content: &[u8],
+        remote_path: &str,
+        progress: &mut Progress,
+    ) -> Result<()> {
+        self.raw_exec_checked(&format!(
+            "import ubinascii, os\nf = open({}, 'wb')\nw = f.write\nd = ubinascii.a2b_base64",
+            py_str(remote_path)
+        ))?;
+        for chunk in content.chunks(self.chunk_size) {
+            self.raw_exec_checked(&format!("w(d('{}'))", base64_encode(chunk)))?;
+            progress.add(chunk.len());
+        }
+        self.raw_exec_checked("f.close()\ndel f, w, d")?;
+
+        let output =
+            self.raw_exec_checked(&format!("print(os.stat({})[6])", py_str(remote_path)))?;
+        let size: usize = output
+            .trim()
+            .parse()
+            .with_context(|| format!("Unexpected size report: {}", output.trim()))?;
+        if size != content.len() {
+            anyhow::bail!(
+                "Size mismatch: {} bytes on the device, {} bytes expected",
+                size,
+                content.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Uploads the files below `local_dir` into `remote_dir` in one session,
+    /// creating the remote directories first
+    fn put_tree(&mut self, local_dir: &Path, remote_dir: &str, filter: &PathFilter) -> Result<()> {
+        let (dirs, files) = scan_local_tree(local_dir, filter)?;
+        let mut remote_dirs = remote_ancestors(remote_dir);
+        remote_dirs.extend(dirs.iter().map(|dir| join_remote(remote_dir, dir)));
+
+        let size = local_size(local_dir, &files)?;
+
+        let total = self.in_session(|device, upyos| {
+            device.warn_if_short_of_space(remote_dir, size)?;
+            device
+                .make_remote_dirs(&remote_dirs)
+                .with_context(|| format!("Error creating directories in '{}'", remote_dir))?;
+            let mut total = 0;
+            for file in &files {
+                total += device.put_tree_file(
+                    &local_dir.join(file),
+                    &join_remote(remote_dir, file),
+                    upyos,
+                )?;
+            }
+            Ok(total)
+        })?;
+
+        println!(
+            "✓ {} files uploaded from '{}' to '{}' ({} bytes)",
+            files.len(),
+            local_dir.display(),
+            remote_dir,
+            total
+        );
+        Ok(())
+    }
+
+    /// Runs `f` in one session: inside the raw REPL in REPL mode, which is
+    /// left again afterwards, or in the upyOS shell. `f` is told whether the
+    /// device runs upyOS.
+    fn in_session<T>(&mut self, f: impl FnOnce(&mut Self, bool) -> Result<T>) -> Result<T> {
+        let upyos = self.mode == DeviceMode::UpyOS;
+        if upyos {
+            return f(self, true);
+        }
+        self.ensure_repl_mode()?;
+        self.enter_raw_repl()?;
+        let result = f(self, false);
+        let exited = self.exit_raw_repl();
+        let value = result?;
+        exited?;
+        Ok(value)
+    }
+
+    /// Creates each of `dirs` that doesn't exist yet, in order. Runs in the
+    /// current session like `run_script`.
+    fn make_remote_dirs(&mut self, dirs: &[String]) -> Result<()> {
+        if dirs.is_empty() {
+            return Ok(());
+        }
+        self.run_script(&format!(
+            "import os\nfor d in {}:\n    try:\n        os.mkdir(d)\n    except OSError as e:\n        if e.args[0] != 17:\n            raise",
+            py_list(dirs)
+        ))?;
+        Ok(())
+    }
+
+    /// The paths in `dirs` that don't exist on the device, which are the ones
+    /// `make_remote_dirs` would create. Runs in the current session like
+    /// `run_script`.
+    fn missing_remote_dirs(&mut self, dirs: &[String]) -> Result<Vec<String>> {
+        if dirs.is_empty() {
+            return Ok(Vec::new());
+        }
+        let output = self.run_script(&format!(
+            "import os\nfor i, d in enumerate({}):\n    try:\n        os.stat(d)\n    except OSError:\n        print(i)",
+            py_list(dirs)
+        ))?;
+        output
+            .split_whitespace()
+            .map(|i| {
+                i.parse::<usize>()
+                    .ok()
+                    .and_then(|i| dirs.get(i).cloned())
+                    .with_context(|| format!("Unexpected output from device: {}", output.trim()))
+            })
+            .collect()
+    }
+
+    /// Uploads one file of `put_tree`. In REPL mode the raw REPL is already active.
+    fn put_tree_file(
+        &mut self,
+        local_path: &Path,
+        remote_path: &str,
+        upyos: bool,
+    ) -> Result<usize> {
+        let content = std::fs::read(local_path)
+            .with_context(|| format!("Could not read {}", local_path.display()))?;
+        let mut progress = Progress::new(
+            &format!("Uploading {}", remote_path),
+            Some(content.len()),
+            self.show_progress,
+        );
+        let result = match self.port.put_file(remote_path, &content, &mut progress) {
+            Some(result) => result,
+            None if upyos => self.write_remote_file_upyos(&content, remote_path, &mut progress),
+            None => {
+                let result = self.write_remote_file(&content, remote_path, &mut progress);
+                if result.is_err() {
+                    let _ = self.raw_exec_checked("f.close()");
+                }
+                result
+            }
+        };
+        result.with_context(|| format!("Error uploading file '{}'", remote_path))?;
+        progress.finish();
+
+        println!(
+            "✓ File '{}' uploaded to '{}' ({} bytes)",
+            local_path.display(),
+            remote_path,
+            content.len()
+        );
+        Ok(content.len())
+    }
+
+    /// Makes `remote_dir` match `local_dir`: uploads new and changed files
+    /// and, with `delete`, removes remote files that don't exist locally.
+    /// With `dry_run` only the plan is printed.
+    fn sync(
+        &mut self,
+        local_dir: &Path,
+        remote_dir: &str,
+        filter: &PathFilter,
+        delete: bool,
+        dry_run: bool,
+    ) -> Result<()> {
+        let (dirs, files) = scan_local_tree(local_dir, filter)?;
+        self.in_session(|device, upyos| {
+            let plan = device.sync_plan(local_dir, remote_dir, filter, &dirs, &files, delete)?;
+            if dry_run {
+                println!(
+                    "Sync plan for '{}' -> '{}':",
+                    local_dir.display(),
+                    remote_dir
+                );
+                for dir in &plan.mkdirs {
+                    println!("  mkdir   {}", dir);
+                }
+                for (file, reason) in &plan.uploads {
+                    println!("  upload  {} ({})", join_remote(remote_dir, file), reason);
+                }
+                for path in plan.delete_files.iter().chain(&plan.delete_dirs) {
+                    println!("  delete  {}", path);
+                }
+                println!(
+                    "{} to upload, {} to delete, {} unchanged (dry run, nothing was changed)",
+                    plan.uploads.len(),
+                    plan.delete_files.len() + plan.delete_dirs.len(),
+                    plan.unchanged
+                );
+                return Ok(());
+            }
+
+            let uploads: Vec<String> = plan.uploads.iter().map(|(f, _)| f.clone()).collect();
+            device.warn_if_short_of_space(remote_dir, local_size(local_dir, &uploads)?)?;
+            device.make_remote_dirs(&plan.mkdirs)?;
+            for (file, _) in &plan.uploads {
+                device.put_tree_file(
+                    &local_dir.join(file),
+                    &join_remote(remote_dir, file),
+                    upyos,
+                )?;
+            }
+            if !plan.delete_files.is_empty() || !plan.delete_dirs.is_empty() {
+                device.run_script(&format!(
+                    "import os\nfor p in {}:\n    os.remove(p)\nfor p in {}:\n    os.rmdir(p)",
+                    py_list(&plan.delete_files),
+                    py_list(&plan.delete_dirs)
+                ))?;
+                for path in plan.delete_files.iter().chain(&plan.delete_dirs) {
+                    println!("✓ Deleted '{}'", path);
+                }
+            }
+            println!(
+                "✓ Synced '{}' to '{}': {} uploaded, {} deleted, {} unchanged",
+                local_dir.display(),
+                remote_dir,
+                plan.uploads.len(),
+                plan.delete_files.len() + plan.delete_dirs.len(),
+                plan.unchanged
+            );
+            Ok(())
+        })
+    }
+
+    /// Compares the local files with the device. Files of equal size are
+    /// compared by SHA-256, or by modification time on boards without
+    /// `hashlib.sha256`. Runs in the current session.
+    fn sync_plan(
+        &mut self,
+        local_dir: &Path,
+        remote_dir: &str,
+        filter: &PathFilter,
+        dirs: &[String],
+        files: &[String],
+        delete: bool,
+    ) -> Result<SyncPlan> {
+        let remote_entries = self
+            .remote_tree(remote_dir)
+            .with_context(|| format!("Error listing '{}'", remote_dir))?;
+        let mut plan = SyncPlan::default();
+        if remote_entries.is_none() {
+            plan.mkdirs = self.missing_remote_dirs(&remote_ancestors(remote_dir))?;
+        }
+        let prefix = join_remote(remote_dir, "");
+        let remote: BTreeMap<String, DirEntry> = remote_entries
+            .unwrap_or_default()
+            .into_iter()
+            .map(|e| {
+                (
+                    e.path.strip_prefix(&prefix).unwrap_or(&e.path).to_string(),
+                    e,
+                )
+            })
+            .collect();
+
+        for dir in dirs {
+            if !remote.get(dir).is_some_and(|e| e.is_dir) {
+                plan.mkdirs.push(join_remote(remote_dir, dir));
+            }
+        }
+
+        let mut same_size = Vec::new();
+        for file in files {
+            let local_path = local_dir.join(file);
+            let size = std::fs::metadata(&local_path)
+                .with_context(|| format!("Could not read {}", local_path.display()))?
+                .len() as usize;
+            match remote.get(file) {
+                Some(entry) if !entry.is_dir && entry.size == size => same_size.push(file),
+                Some(entry) if !entry.is_dir => plan.uploads.push((file.clone(), "changed")),
+                _ => plan.uploads.push((file.clone(), "new")),
+            }
+        }
+
+        let paths: Vec<String> = same_size
+            .iter()
+            .map(|file| join_remote(remote_dir, file))
+            .collect();
+        let hashes = self.remote_hashes(&paths)?;
+        for (file, hash) in same_size.into_iter().zip(hashes) {
+            let local_path = local_dir.join(file);
+            let unchanged = match hash {
+                Some(hash) => {
+                    let content = std::fs::read(&local_path)
+                        .with_context(|| format!("Could not read {}", local_path.display()))?;
+                    sha256::hex_digest(&content) == hash
+                }
+                None => local_mtime(&local_path)? <= remote[file].mtime,
+            };
+            if unchanged {
+                plan.unchanged += 1;
+            } else {
+                plan.uploads.push((file.clone(), "changed"));
+            }
+        }
+        plan.uploads.sort();
+
+        if delete {
+            let local_files: BTreeSet<&String> = files.iter().collect();
+            let local_dirs: BTreeSet<&String> = dirs.iter().collect();
+            for (path, entry) in &remote {
+                if !entry.is_dir
+                    && filter.includes_file(path)
+                    && !filter.excludes_tree(path)
+                    && !local_files.contains(path)
+                {
+                    plan.delete_files.push(entry.path.clone());
+                }
+            }
+            // Only directories left empty, deepest first
+            if !filter.has_includes() {
+                for (path, entry) in remote.iter().rev() {
+                    let kept = |p: &String| {
+                        p.starts_with(&format!("{}/", path))
+                            && !plan.delete_files.contains(&remote[p].path)
+                            && !plan.delete_dirs.contains(&remote[p].path)
+                    };
+                    if entry.is_dir
+                        && !filter.excludes_tree(path)
+                        && !local_dirs.contains(path)
+                        && !remote.keys().any(kept)
+                    {
+                        plan.delete_dirs.push(entry.path.clone());
+                    }
+                }
+            }
+        }
+        Ok(plan)
+    }
+
+    /// SHA-256 of each file on the device, `None` when the firmware has no
+    /// `hashlib.sha256`. Runs in the current session like `run_script`.
+    fn remote_hashes(&mut self, paths: &[String]) -> Result<Vec<Option<String>>> {
+        if paths.is_empty() {
+            return Ok(Vec::new());
+        }
+        let setup = r#"import ubinascii
+try:
+    import hashlib
+except ImportError:
+    import uhashlib as hashlib
+def hf(p):
+    if not hasattr(hashlib, 'sha256'):
+        return '-'
+    h = hashlib.sha256()
+    with open(p, 'rb') as f:
+        while True:
+            b = f.read(512)
+            if not b:
+                break
+            h.update(b)
+    return ubinascii.hexlify(h.digest()).decode()"#;
+        let calls: Vec<String> = paths
+            .iter()
+            .map(|path| format!("print(hf({}))", py_str(path)))
+            .collect();
+
+        let output = if self.mode == DeviceMode::UpyOS {
+            // Helper scripts don't share state, so hash everything in one
+            self.run_script(&format!("{}\n{}", setup, calls.join("\n")))?
+        } else {
+            // One command per file keeps each within the raw REPL timeout
+            self.run_script(setup)?;
+            let mut output = String::new();
+            for call in &calls {
+                output.push_str(&self.run_script(call)?);
+            }
+            output
+        };
+
+        let hashes: Vec<Option<String>> = output
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| (line != "-").then(|| line.to_string()))
+            .collect();
+        if hashes.len() != paths.len() {
+            anyhow::bail!("Unexpected hash report:\n{}", output.trim());
+        }
+        Ok(hashes)
+    }
+
+    /// Warns when `bytes` more data won't fit on the filesystem holding
+    /// `path`, counting the space of a file that gets replaced. Runs in the
+    /// current session like `run_script`.
+    fn warn_if_short_of_space(&mut self, path: &str, bytes: usize) -> Result<()> {
+        let output = self.run_script(&format!(
+            r#"import os
+p = {}
+e = 0
+try:
+    s = os.stat(p)
+    if not s[0] & 0x4000:
+        e = s[6]
+except OSError:
+    pass
+# The destination may not exist yet: use the nearest existing parent
+while True:
+    try:
+        v = os.statvfs(p)
+        print((v[1] or v[0]) * v[4], e)
+        break
+    except Exception:
+        if p in ('', '/', '.'):
+            print(-1, e)
+            break
+        p = p.rstrip('/')
+        i = p.rfind('/')
+        p = '/' if i == 0 else p[:i] if i > 0 else '.'"#,
+            py_str(path)
+        ))?;
+        let mut fields = output.split_whitespace().map(|f| f.parse::<i64>().ok());
+        if let (Some(Some(free)), Some(Some(replaced))) = (fields.next(), fields.next())
+            && free >= 0
+            && bytes as i64 > free + replaced
+        {
+            eprintln!(
+                "[WARNING] Uploading {} but only {} is free on the device",
+                progress::human_bytes(bytes as f64),
+                progress::human_bytes(free as f64)
+            );
+        }
+        Ok(())
+    }
+
+    /// Usage of the root filesystem and of each top-level directory that
+    /// is a different filesystem (such as `/sd`). upyOS reports it with its
+    /// own `df` command.
+    fn filesystems(&mut self) -> Result<Vec<FsUsage>> {
+        if self.mode == DeviceMode::UpyOS {
+            return parse_df(&self.shell_command("df")?);
+        }
+        let output = self.in_session(|device, _| {
+            device.run_script(
+                r#"import os
+def q(p):
+    try:
+        v = os.statvfs(p)
+    except Exception:
+        return None
+    return (v[1] or v[0], v[2], v[3], v[4]) if v[2] else None
+r = q('/')
+if r:
+    print(r[0], r[1], r[2], r[3], '/')
+for n in os.listdir('/'):
+    p = '/' + n
+    try:
+        d = os.stat(p)[0] & 0x4000
+    except OSError:
+        d = 0
+    v = q(p) if d else None
+    if v and v != r:
+        print(v[0], v[1], v[2], v[3], p)"#,
+            )
+        })?;
+
+        output
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let fields: Vec<&str> = line.splitn(5, ' ').collect();
+                let number = |i: usize| fields.get(i).and_then(|f| f.parse::<u64>().ok());
+                match (number(0), number(1), number(2), number(3), fields.get(4)) {
+                    (Some(block_size), Some(blocks), Some(free), Some(avail), Some(mount)) => {
+                        Ok(FsUsage {
+                            mount: mount.to_string(),
+                            block_size,
+                            blocks,
+                            free_blocks: free,
+                            avail_blocks: avail,
+                        })
+                    }
+                    _ => anyhow::bail!("Unexpected filesystem report: {}", line),
+                }
+            })
+            .collect()
+    }
+
+    /// Runs calls to the `FS_HELPERS` functions, one per line, in the
+    /// current session. Stops at the first failure and returns it as an error.
+    fn run_fs_script(&mut self, calls: &[String]) -> Result<()> {
+        let output = self.run_script(&format!(
+            "{}\ntry:\n    {}\nexcept E as x:\n    print('E:%s' % x)",
+            FS_HELPERS,
+            calls.join("\n    ")
+        ))?;
+        if let Some(message) = output.lines().find_map(|l| l.trim().strip_prefix("E:")) {
+            anyhow::bail!("{}", message);
+        }
+        Ok(())
+    }
+
+    /// `run_fs_script` in a session of its own
+    fn fs_command(&mut self, calls: &[String]) -> Result<()> {
+        self.in_session(|device, _| device.run_fs_script(calls))
+    }
+
+    fn mkdir(&mut self, paths: &[String], parents: bool) -> Result<()> {
+        let calls: Vec<String> = paths
+            .iter()
+            .map(|path| {
+                if parents {
+                    format!("mkdirs({}, True)", py_list(&remote_ancestors(path)))
+                } else {
+                    format!("mkdirs([{}], False)", py_str(path))
+                }
+            })
+            .collect();
+        self.fs_command(&calls)?;
+        for path in paths {
+            println!("✓ Directory '{}' created", path);
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, paths: &[String], recursive: bool) -> Result<()> {
+        let calls: Vec<String> = paths
+            .iter()
+            .map(|path| {
+                format!(
+                    "rm({}, {})",
+                    py_str(path),
+                    if recursive { "True" } else { "False" }
+                )
+            })
+            .collect();
+        self.fs_command(&calls)?;
+        for path in paths {
+            println!("✓ '{}' removed", path);
+        }
+        Ok(())
+    }
+
+    fn rmdir(&mut self, paths: &[String]) -> Result<()> {
+        let calls: Vec<String> = paths
+            .iter()
+            .map(|path| format!("rmdir({})", py_str(path)))
+            .collect();
+        self.fs_command(&calls)?;
+        for path in paths {
+            println!("✓ Directory '{}' removed", path);
+        }
+        Ok(())
+    }
+
+    /// Moves or renames `source`; into `dest` when that is a directory
+    fn rename(&mut self, source: &str, dest: &str) -> Result<()> {
+        self.fs_command(&[format!("mv({}, {})", py_str(source), py_str(dest))])?;
+        println!("✓ '{}' moved to '{}'", source, dest);
+        Ok(())
+    }
+
+    /// Copies a file on the device; into `dest` when that is a directory
+    fn copy(&mut self, source: &str, dest: &str) -> Result<()> {
+        self.fs_command(&[format!("cp({}, {})", py_str(source), py_str(dest))])?;
+        println!("✓ '{}' copied to '{}'", source, dest);
+        Ok(())
+    }
+
+    /// Creates empty files. Existing files are left as they are, as
+    /// MicroPython can't change modification times.
+    fn touch(&mut self, paths: &[String]) -> Result<()> {
+        let calls: Vec<String> = paths
+            .iter()
+            .map(|path| format!("touch({})", py_str(path)))
+            .collect();
+        self.fs_command(&calls)?;
+        for path in paths {
+            println!("✓ '{}' touched", path);
+        }
+        Ok(())
+    }
+
+    /// Writes files from the device to stdout, unchanged
+    fn cat(&mut self, paths: &[String]) -> Result<()> {
+        self.in_session(|device, upyos| {
+            let mut stdout = io::stdout();
+            for path in paths {
+                device.run_fs_script(&[format!("need({}, 1)", py_str(path))])?;
+                let mut progress = Progress::new("", None, false);
+                let content = match device.port.get_file(path, &mut progress) {
+                    Some(result) => result,
+                    None if upyos => device.read_remote_file_upyos(path, &mut progress),
+                    None => {
+                        let result = device.read_remote_file(path, &mut progress);
+                        if result.is_err() {
+                            let _ = device.raw_exec_checked("f.close()");
+                        }
+                        result
+                    }
+                }
+                .with_context(|| format!("Error reading remote file '{}'", path))?;
+                stdout.write_all(&content)?;
+            }
+            stdout.flush()?;
+            Ok(())
+        })
+    }
+
+    fn put_file_upyos(
+        &mut self,
+        content: &[u8],
+        local_path: &Path,
+        remote_path: &str,
+        progress: &mut Progress,
+    ) -> Result<()> {
+        self.ensure_upyos_mode()?;
+        self.warn_if_short_of_space(remote_path, content.len())?;
+        self.write_remote_file_upyos(content, remote_path, progress)
+            .with_context(|| format!("Error uploading file '{}'", remote_path))?;
+        progress.finish();
+
+        println!(
+            "✓ File '{}' uploaded to '{}' ({} bytes)",
+            local_path.display(),
+            remote_path,
+            content.len()
+        );
+        Ok(())
+    }
+
+    /// Binary-safe upload for upyOS: the file is sent as base64 text with
+    /// `fileup`, in parts small enough for `fileup`'s buffer, and a helper
+    /// script run with `python` decodes each part into the destination.
+    fn write_remote_file_upyos(
+        &mut self,
+        content: &[u8],
+        remote_path: &str,
+        progress: &mut Progress,
+    ) -> Result<()> {
+        // 57 bytes make one 76-character base64 line
+        let lines: Vec<String> = content.chunks(57).map(base64_encode).collect();
+        let lines_per_part = UPYOS_PART_SIZE / 77;
+        let mut parts: Vec<String> = lines
+            .chunks(lines_per_part)
+            .map(|part| part.join("\n"))
+            .collect();
+        if parts.is_empty() {
+            parts.push(String::new());
+        }
+
+        let part_count = parts.len();
+        for (i, part) in parts.iter().enumerate() {
+            let offset = i * lines_per_part * 57;
+            self.fileup(UPYOS_PART_PATH, part, &mut |lines| {
+                progress.set((offset + lines * 57).min(content.len()))
+            })?;
+            let script = format!(
+                r#"import ubinascii, os
+with open({part}) as i, open({dest}, '{mode}') as o:
+    for l in i:
+        l = l.strip()
+        if l:
+            o.write(ubinascii.a2b_base64(l))
+os.remove({part})
+print('{marker}', os.stat({dest})[6])"#,
+                part = py_str(UPYOS_PART_PATH),
+                dest = py_str(remote_path),
+                mode = if i == 0 { "wb" } else { "ab" },
+                marker = UPYOS_END_MARKER,
+            );
+            let output = self.run_upyos_helper(&script, &mut |_| {})?;
+            let Some(reported) = output
+                .lines()
+                .find_map(|l| l.trim().strip_prefix(UPYOS_END_MARKER))
+            else {
+                anyhow::bail!("Helper script failed:\n{}", output.trim());
+            };
+
+            if i + 1 == part_count {
+                let size: usize = reported
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("Unexpected size report: {}", reported.trim()))?;
+                if size != content.len() {
+                    anyhow::bail!(
+                        "Size mismatch after upload: {} bytes on the device, {} bytes expected",
+                        size,
+                        content.len()
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs a command in the upyOS shell and returns its output, without the
+    /// echoed command line and the final prompt. Waits for the prompt as long
+    /// as the device keeps sending data.
+    fn shell_command(&mut self, command: &str) -> Result<String> {
+        self.shell_command_with(command, &mut |_| {})
+    }
+
+    /// `shell_command`, passing each piece of output to `on_data` as it arrives
+    fn shell_command_with(
+        &mut self,
+        command: &str,
+        on_data: &mut dyn FnMut(&[u8]),
+    ) -> Result<String> {
+        let mut discard = [0u8; 1024];
+        while self.read_available(&mut discard)? > 0 {}
+
+        self.write(format!("{}\r", command).as_bytes())?;
+
+        const PROMPT: &[u8] = b" $: ";
+        let idle_timeout = Duration::from_secs(10);
+        let mut response = Vec::new();
+        let mut buf = [0u8; 1024];
+        let mut last_data = std::time::Instant::now();
+
+        while !response.windows(PROMPT.len()).any(|w| w == PROMPT) {
+            if last_data.elapsed() > idle_timeout {
+                anyhow::bail!("Timeout waiting for the shell prompt after '{}'", command);
+            }
+            let n = self.read_available(&mut buf)?;
+            if n > 0 {
+                response.extend_from_slice(&buf[..n]);
+                on_data(&buf[..n]);
+                last_data = std::time::Instant::now();
+            }
+        }
+
+        let output = String::from_utf8_lossy(&response);
+        let mut lines: Vec<&str> = output.lines().collect();
+        // Last line is the prompt, first line the echo
+        lines.pop();
+        if lines.first().is_some_and(|l| l.trim() == command) {
+            lines.remove(0);
+        }
+        Ok(lines.join("\n"))
+    }
+
+    /// Uploads a generated Python script, runs it with upyOS's `python`
+    /// command and removes it again. Returns the script's output.
+    fn run_upyos_helper(&mut self, script: &str, on_data: &mut dyn FnMut(&[u8])) -> Result<String> {
+        self.fileup(UPYOS_HELPER_PATH, script, &mut |_| {})?;
+        let output = self.shell_command_with(
+            &format!("python {}", shell_arg(UPYOS_HELPER_PATH)?),
+            on_data,
+        );
+        let cleanup = self.shell_command(&format!("rm {}", shell_arg(UPYOS_HELPER_PATH)?));
+        let output = output?;
+        cleanup?;
+        Ok(output)
+    }
+
+    /// Writes a text file with upyOS's `fileup`, which reads it line by line.
+    /// Only suited to short text: `fileup` keeps the file in memory and
+    /// doesn't preserve line endings. `on_line` gets the number of lines sent.
+    fn fileup(
+        &mut self,
+        remote_path: &str,
+        content: &str,
+        on_line: &mut dyn FnMut(usize),
+    ) -> Result<()> {
         // Use upyOS fileup command
-        let cmd = format!("fileup {}\r", remote_path);
+        let cmd = format!("fileup {}\r", shell_arg(remote_path)?);
         self.write(cmd.as_bytes())?;
 
         // Wait for fileup to start and show message
@@ -537,7 +2072,7 @@ print('OK')"#,
         // Send file content line by line, waiting for ">" prompt
         let lines: Vec<&str> = content.lines().collect();
 
-        for line in lines {
+        for (sent, line) in lines.into_iter().enumerate() {
             // Wait for ">" prompt
             let mut prompt_buf = [0u8; 256];
             let prompt_start = std::time::Instant::now();
@@ -559,6 +2094,7 @@ print('OK')"#,
             // Send the line
             self.write(line.as_bytes())?;
             self.write(b"\r")?;
+            on_line(sent + 1);
         }
 
         // Send Ctrl+D to end upload
@@ -587,117 +2123,330 @@ print('OK')"#,
         if resp_str.contains("Can't overwrite system file") {
             anyhow::bail!("Cannot overwrite system file '{}'", remote_path);
         }
-
-        println!(
-            "✓ File '{}' uploaded to '{}' ({} bytes)",
-            local_path.display(),
-            remote_path,
-            content.len()
-        );
         Ok(())
     }
 
     fn get_file(&mut self, remote_path: &str, local_path: &PathBuf) -> Result<()> {
+        // Transports with their own file-transfer protocol (WebREPL) don't need the REPL
+        let mut progress = Progress::new(
+            &format!("Downloading {}", remote_path),
+            None,
+            self.show_progress,
+        );
+        if let Some(result) = self.port.get_file(remote_path, &mut progress) {
+            let content =
+                result.with_context(|| format!("Error reading remote file '{}'", remote_path))?;
+            progress.finish();
+            std::fs::write(local_path, &content)
+                .with_context(|| format!("Could not write {}", local_path.display()))?;
+            println!(
+                "✓ File '{}' downloaded to '{}' ({} bytes)",
+                remote_path,
+                local_path.display(),
+                content.len()
+            );
+            return Ok(());
+        }
+
         match self.mode {
-            DeviceMode::MicroPythonRepl => self.get_file_repl(remote_path, local_path),
-            DeviceMode::UpyOS => self.get_file_upyos(remote_path, local_path),
+            DeviceMode::MicroPythonRepl => {
+                self.get_file_repl(remote_path, local_path, &mut progress)
+            }
+            DeviceMode::UpyOS => self.get_file_upyos(remote_path, local_path, &mut progress),
             DeviceMode::Unknown => {
                 // Try REPL mode first
-                self.get_file_repl(remote_path, local_path)
+                self.get_file_repl(remote_path, local_path, &mut progress)
             }
         }
     }
 
-    fn get_file_repl(&mut self, remote_path: &str, local_path: &PathBuf) -> Result<()> {
-        let cmd = format!(
-            r#"import ubinascii
+    /// Downloads through the raw REPL one chunk per command, so the device
+    /// never holds more than one chunk in memory. The local file is only
+    /// written once length and SHA-256 match what the device reports.
+    fn get_file_repl(
+        &mut self,
+        remote_path: &str,
+        local_path: &PathBuf,
+        progress: &mut Progress,
+    ) -> Result<()> {
+        self.ensure_repl_mode()?;
+        self.enter_raw_repl()?;
+
+        let result = self.read_remote_file(remote_path, progress);
+        if result.is_err() {
+            let _ = self.raw_exec_checked("f.close()");
+        }
+        self.exit_raw_repl()?;
+        let content =
+            result.with_context(|| format!("Error reading remote file '{}'", remote_path))?;
+        progress.finish();
+
+        std::fs::write(local_path, &content)
+            .with_context(|| format!("Could not write {}", local_path.display()))?;
+
+        println!(
+            "✓ File '{}' downloaded to '{}' ({} bytes)",
+            remote_path,
+            local_path.display(),
+            content.len()
+        );
+        Ok(())
+    }
+
+    /// Reads a file from the device and verifies it. Must be called in the raw REPL.
+    fn read_remote_file(&mut self, remote_path: &str, progress: &mut Progress) -> Result<Vec<u8>> {
+        let output = self.raw_exec_checked(&format!(
+            r#"import ubinascii, os
 try:
-    with open('{}', 'rb') as f:
-        data = f.read()
-        print(ubinascii.b2a_base64(data).decode().strip())
-except OSError as e:
-    print('Error:', e)"#,
-            remote_path
+    import hashlib
+except ImportError:
+    import uhashlib as hashlib
+h = hashlib.sha256() if hasattr(hashlib, 'sha256') else None
+f = open({0}, 'rb')
+print(os.stat({0})[6])"#,
+            py_str(remote_path)
+        ))?;
+        let size: usize = output
+            .trim()
+            .parse()
+            .with_context(|| format!("Unexpected size report: {}", output.trim()))?;
+        progress.set_total(size);
+
+        let mut content = Vec::with_capacity(size);
+        let read_chunk = format!(
+            "b = f.read({})\nif h: h.update(b)\nprint(ubinascii.b2a_base64(b).decode().strip())",
+            self.chunk_size
+        );
+        while content.len() < size {
+            let output = self.raw_exec_checked(&read_chunk)?;
+            let chunk = base64_decode(output.trim())?;
+            if chunk.is_empty() {
+                anyhow::bail!("File ended after {} of {} bytes", content.len(), size);
+            }
+            content.extend_from_slice(&chunk);
+            progress.set(content.len());
+        }
+
+        let output = self.raw_exec_checked(
+            "f.close()\nprint(ubinascii.hexlify(h.digest()).decode() if h else '')\ndel f, h",
+        )?;
+        verify_download(&content, size, output.trim())?;
+        Ok(content)
+    }
+
+    fn get_file_upyos(
+        &mut self,
+        remote_path: &str,
+        local_path: &PathBuf,
+        progress: &mut Progress,
+    ) -> Result<()> {
+        self.ensure_upyos_mode()?;
+        let content = self
+            .read_remote_file_upyos(remote_path, progress)
+            .with_context(|| format!("Error reading remote file '{}'", remote_path))?;
+        progress.finish();
+
+        std::fs::write(local_path, &content)
+            .with_context(|| format!("Could not write {}", local_path.display()))?;
+
+        println!(
+            "✓ File '{}' downloaded to '{}' ({} bytes)",
+            remote_path,
+            local_path.display(),
+            content.len()
         );
+        Ok(())
+    }
 
-        let output = self.exec_command(&cmd)?;
+    /// Binary-safe download for upyOS: a helper script prints the file as
+    /// base64 lines between markers, followed by its SHA-256
+    fn read_remote_file_upyos(
+        &mut self,
+        remote_path: &str,
+        progress: &mut Progress,
+    ) -> Result<Vec<u8>> {
+        let script = format!(
+            r#"import ubinascii, os
+try:
+    import hashlib
+except ImportError:
+    import uhashlib as hashlib
+h = hashlib.sha256() if hasattr(hashlib, 'sha256') else None
+print('{begin}', os.stat({src})[6])
+with open({src}, 'rb') as f:
+    while True:
+        b = f.read(570)
+        if not b:
+            break
+        if h:
+            h.update(b)
+        print(ubinascii.b2a_base64(b).decode().strip())
+print('{end}', ubinascii.hexlify(h.digest()).decode() if h else '')"#,
+            src = py_str(remote_path),
+            begin = UPYOS_BEGIN_MARKER,
+            end = UPYOS_END_MARKER,
+        );
 
-        if output.contains("Error:") {
-            anyhow::bail!("Error reading remote file: {}", output);
+        // Estimate progress from the amount of base64 received: every 570
+        // bytes of the file arrive as a 760-character line plus CRLF
+        let mut header = Vec::new();
+        let mut received = None;
+        let output = self.run_upyos_helper(&script, &mut |data| match received.as_mut() {
+            Some(received) => {
+                *received += data.len();
+                progress.set(*received * 570 / 762);
+            }
+            None => {
+                header.extend_from_slice(data);
+                let text = String::from_utf8_lossy(&header);
+                if let Some(start) = text.find(UPYOS_BEGIN_MARKER)
+                    && let Some(end) = text[start..].find('\n')
+                {
+                    let line = &text[start + UPYOS_BEGIN_MARKER.len()..start + end];
+                    if let Ok(size) = line.trim().parse() {
+                        progress.set_total(size);
+                    }
+                    received = Some(text.len() - start - end - 1);
+                }
+            }
+        })?;
+        let mut lines = output.lines().map(str::trim);
+        let size = lines
+            .by_ref()
+            .find_map(|l| l.strip_prefix(UPYOS_BEGIN_MARKER))
+            .and_then(|size| size.trim().parse::<usize>().ok())
+            .with_context(|| format!("Helper script failed:\n{}", output.trim()))?;
+
+        let mut content = Vec::with_capacity(size);
+        let mut device_hash = None;
+        for line in lines {
+            if let Some(hash) = line.strip_prefix(UPYOS_END_MARKER) {
+                device_hash = Some(hash.trim());
+                break;
+            }
+            content.extend_from_slice(&base64_decode(line)?);
         }
+        let device_hash =
+            device_hash.with_context(|| format!("Helper script failed:\n{}", output.trim()))?;
+        verify_download(&content, size, device_hash)?;
+        progress.set(content.len());
+        Ok(content)
+    }
 
-        // Extract base64 from output
-        let b64_data: String = output
-            .lines()
-            .map(|s| s.trim())
-            .filter(|s| !s.is_empty() && !s.contains(">>>") && !s.contains("OK"))
-            .collect();
+    /// Runs generated code in the current session: in the raw REPL, which
+    /// must be active, or as a upyOS helper script. Returns its output.
+    fn run_script(&mut self, script: &str) -> Result<String> {
+        if self.mode != DeviceMode::UpyOS {
+            return self.raw_exec_checked(script);
+        }
+        let output = self.run_upyos_helper(script, &mut |_| {})?;
+        if let Some(start) = output.find("Traceback (most recent call last)") {
+            anyhow::bail!("Device raised an exception:\n{}", &output[start..]);
+        }
+        Ok(output)
+    }
 
-        if b64_data.is_empty() {
-            anyhow::bail!("Could not read file '{}'", remote_path);
+    /// Files and directories below `dir`, parents before their contents,
+    /// or `None` when `dir` doesn't exist. Runs in the current session like
+    /// `run_script`.
+    fn remote_tree(&mut self, dir: &str) -> Result<Option<Vec<DirEntry>>> {
+        let output = self.run_script(&format!(
+            "{}\ntry:\n    os.stat({1})\nexcept OSError:\n    print('-')\nelse:\n    w({1}, True)",
+            TREE_WALKER,
+            py_str(dir)
+        ))?;
+        if output.trim() == "-" {
+            return Ok(None);
         }
+        parse_entries(&output).map(Some)
+    }
 
-        let content = base64_decode(&b64_data)?;
-        let content_len = content.len();
-        std::fs::write(local_path, &content)
-            .with_context(|| format!("Could not write {}", local_path.display()))?;
+    /// Entries of the directory at `path`, and of its subdirectories with
+    /// `recursive`. A file lists as itself.
+    fn list_entries(&mut self, path: &str, recursive: bool) -> Result<Vec<DirEntry>> {
+        let script = format!(
+            "{}\np = {}\ns = os.stat(p)\nif s[0] & 0x4000:\n    w(p, {})\nelse:\n    print('F', s[6], s[8] + o, p)",
+            TREE_WALKER,
+            py_str(path),
+            if recursive { "True" } else { "False" }
+        );
+        self.in_session(|device, _| device.run_script(&script))
+            .and_then(|output| parse_entries(&output))
+    }
+
+    /// Downloads `remote_dir` and everything below it into `local_dir` in
+    /// one session, recreating the directory structure
+    fn get_tree(&mut self, remote_dir: &str, local_dir: &Path) -> Result<()> {
+        let (count, total) =
+            self.in_session(|device, upyos| device.get_tree_files(remote_dir, local_dir, upyos))?;
 
         println!(
-            "✓ File '{}' downloaded to '{}' ({} bytes)",
-            remote_path,
-            local_path.display(),
-            content_len
+            "✓ {} files downloaded from '{}' to '{}' ({} bytes)",
+            count,
+            remote_dir,
+            local_dir.display(),
+            total
         );
         Ok(())
     }
 
-    fn get_file_upyos(&mut self, remote_path: &str, local_path: &PathBuf) -> Result<()> {
-        self.ensure_upyos_mode()?;
-
-        // Use cat command to read file
-        let cmd = format!("cat {}\r", remote_path);
-        self.write(cmd.as_bytes())?;
-
-        // Read response until prompt
-        let mut response = Vec::new();
-        let mut buf = [0u8; 1024];
-        let start = std::time::Instant::now();
-        const PROMPT: &[u8] = b"$:";
+    /// Body of `get_tree`; returns the number of files and bytes downloaded
+    fn get_tree_files(
+        &mut self,
+        remote_dir: &str,
+        local_dir: &Path,
+        upyos: bool,
+    ) -> Result<(usize, usize)> {
+        let entries = self
+            .remote_tree(remote_dir)
+            .with_context(|| format!("Error listing '{}'", remote_dir))?
+            .with_context(|| format!("No such directory on the device: '{}'", remote_dir))?;
+        std::fs::create_dir_all(local_dir)
+            .with_context(|| format!("Could not create {}", local_dir.display()))?;
+
+        let prefix = join_remote(remote_dir, "");
+        let mut count = 0;
+        let mut total = 0;
+        for entry in entries {
+            let relative = entry.path.strip_prefix(&prefix).unwrap_or(&entry.path);
+            let local_path = local_dir.join(relative);
+            if entry.is_dir {
+                std::fs::create_dir_all(&local_path)
+                    .with_context(|| format!("Could not create {}", local_path.display()))?;
+                continue;
+            }
 
-        while start.elapsed().as_secs() < 10 {
-            match self.port.read(&mut buf) {
-                Ok(n) if n > 0 => {
-                    response.extend_from_slice(&buf[..n]);
-                    if response.windows(PROMPT.len()).any(|w| w == PROMPT) {
-                        break;
+            let mut progress = Progress::new(
+                &format!("Downloading {}", entry.path),
+                Some(entry.size),
+                self.show_progress,
+            );
+            let content = match self.port.get_file(&entry.path, &mut progress) {
+                Some(result) => result,
+                None if upyos => self.read_remote_file_upyos(&entry.path, &mut progress),
+                None => {
+                    let result = self.read_remote_file(&entry.path, &mut progress);
+                    if result.is_err() {
+                        let _ = self.raw_exec_checked("f.close()");
                     }
+                    result
                 }
-                Ok(_) => {}
-                Err(_) => thread::sleep(Duration::from_millis(10)),
             }
-        }
-
-        // Parse response - remove command echo and prompt
-        let output = String::from_utf8_lossy(&response);
-        let lines: Vec<&str> = output.lines().collect();
-
-        // Skip first line (command) and last line (prompt)
-        let content_lines: Vec<&str> = if lines.len() > 2 {
-            lines[1..lines.len() - 1].to_vec()
-        } else {
-            lines.to_vec()
-        };
-
-        let content = content_lines.join("\n");
-        std::fs::write(local_path, content)
-            .with_context(|| format!("Could not write {}", local_path.display()))?;
+            .with_context(|| format!("Error reading remote file '{}'", entry.path))?;
+            progress.finish();
 
-        println!(
-            "✓ File '{}' downloaded to '{}'",
-            remote_path,
-            local_path.display()
-        );
-        Ok(())
+            std::fs::write(&local_path, &content)
+                .with_context(|| format!("Could not write {}", local_path.display()))?;
+            println!(
+                "✓ File '{}' downloaded to '{}' ({} bytes)",
+                entry.path,
+                local_path.display(),
+                content.len()
+            );
+            count += 1;
+            total += content.len();
+        }
+        Ok((count, total))
     }
 
     fn soft_reset(&mut self) -> Result<()> {
@@ -709,20 +2458,23 @@ except OSError as e:
     }
 
     fn hard_reset(&mut self) -> Result<()> {
-        // Toggle DTR/RTS for hard reset on many ESP32 boards
         println!("Performing hard reset (DTR/RTS)...");
-        self.port.write_data_terminal_ready(true)?;
-        self.port.write_request_to_send(false)?;
-        thread::sleep(Duration::from_millis(100));
-        self.port.write_data_terminal_ready(false)?;
-        self.port.write_request_to_send(true)?;
-        thread::sleep(Duration::from_millis(100));
-        self.port.write_request_to_send(false)?;
+        self.port.hard_reset()?;
         thread::sleep(Duration::from_millis(1000));
         println!("✓ Hard reset performed");
         Ok(())
     }
 
+    /// Resets through `machine.reset()`, for connections without DTR/RTS
+    fn machine_reset(&mut self) -> Result<()> {
+        self.ensure_repl_mode()?;
+        self.enter_raw_repl()?;
+        self.write_raw_code(b"import machine\nmachine.reset()")?;
+        thread::sleep(Duration::from_millis(1000));
+        println!("✓ Machine reset performed");
+        Ok(())
+    }
+
     fn send_string(&mut self, data: &str, timeout_secs: Option<u64>) -> Result<String> {
         // Clear input buffer
         let mut discard = [0u8; 1024];
@@ -833,10 +2585,7 @@ except OSError as e:
             loop {
                 // Read from serial port
                 match self.read_available(&mut serial_buf) {
-                    Ok(n) if n > 0 => {
-                        stdout.write_all(&serial_buf[..n])?;
-                        stdout.flush()?;
-                    }
+                    Ok(n) if n > 0 => self.show_output(&serial_buf[..n], &mut stdout)?,
                     Ok(_) => {}
                     Err(_) => break,
                 }
@@ -906,10 +2655,7 @@ except OSError as e:
             while running {
                 // Read data from serial port (non-blocking)
                 match self.read_available(&mut serial_buf) {
-                    Ok(n) if n > 0 => {
-                        stdout.write_all(&serial_buf[..n])?;
-                        stdout.flush()?;
-                    }
+                    Ok(n) if n > 0 => self.show_output(&serial_buf[..n], &mut stdout)?,
                     Ok(_) => {}
                     Err(e) => {
                         eprintln!("Error reading serial: {}", e);
@@ -1048,10 +2794,515 @@ except OSError as e:
     }
 }
 
+/// Python that prints one line per entry below a directory with
+/// `w(path, recursive)`: `D|F size mtime path`, parents before their contents
+const TREE_WALKER: &str = r#"import os, time
+# Device timestamps count from 2000 on most ports
+o = 946684800 if time.gmtime(0)[0] == 2000 else 0
+def w(p, r):
+    for e in os.ilistdir(p):
+        c = (p if p.endswith('/') else p + '/') + e[0]
+        s = os.stat(c)
+        if e[1] & 0x4000:
+            print('D', 0, s[8] + o, c)
+            if r:
+                w(c, r)
+        else:
+            print('F', s[6], s[8] + o, c)"#;
+
+/// Parses the output of `TREE_WALKER`, leaving out upyOS helper files
+fn parse_entries(output: &str) -> Result<Vec<DirEntry>> {
+    let mut entries = Vec::new();
+    for line in output.lines().map(|l| l.trim_end_matches('\r')) {
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.splitn(4, ' ');
+        let entry = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some(kind @ ("D" | "F")), Some(size), Some(mtime), Some(path)) => size
+                .parse()
+                .ok()
+                .zip(mtime.parse().ok())
+                .map(|(size, mtime)| DirEntry {
+                    path: path.to_string(),
+                    is_dir: kind == "D",
+                    size,
+                    mtime,
+                }),
+            _ => None,
+        };
+        let entry = entry.with_context(|| format!("Unexpected listing line: {}", line))?;
+        // upyOS helper files exist only while the helper runs
+        if entry.path != UPYOS_HELPER_PATH && entry.path != UPYOS_PART_PATH {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Prints `ls` output: names relative to `path`, in the device's order.
+/// With `long` or `recursive` they are sorted and directories get a `/`,
+/// and `long` adds type, size and modification time.
+fn print_listing(path: &str, mut entries: Vec<DirEntry>, long: bool, recursive: bool) {
+    let detailed = long || recursive;
+    if detailed {
+        entries.sort_by(|a, b| a.path.split('/').cmp(b.path.split('/')));
+    }
+    let prefix = join_remote(path, "");
+    println!("Files in '{}'", path);
+    for entry in entries {
+        let mut name = entry
+            .path
+            .strip_prefix(&prefix)
+            .unwrap_or(&entry.path)
+            .to_string();
+        if entry.is_dir && detailed {
+            name.push('/');
+        }
+        if long {
+            let size = if entry.is_dir {
+                "-".to_string()
+            } else {
+                entry.size.to_string()
+            };
+            println!(
+                "  {} {:>9}  {}  {}",
+                if entry.is_dir { 'd' } else { '-' },
+                size,
+                format_time(entry.mtime),
+                name
+            );
+        } else {
+            println!("  {}", name);
+        }
+    }
+}
+
+/// Prints `path` and the entries below it as a tree
+fn print_tree(path: &str, mut entries: Vec<DirEntry>) {
+    fn children(entries: &[DirEntry], dir: &str, indent: &str) {
+        let parent = |p: &str| match p.rsplit_once('/') {
+            Some(("", _)) => "/".to_string(),
+            Some((parent, _)) => parent.to_string(),
+            None => String::new(),
+        };
+        let list: Vec<&DirEntry> = entries.iter().filter(|e| parent(&e.path) == dir).collect();
+        for (i, entry) in list.iter().enumerate() {
+            let last = i + 1 == list.len();
+            let name = entry.path.rsplit('/').next().unwrap_or(&entry.path);
+            println!("{}{}{}", indent, if last { "└── " } else { "├── " }, name);
+            if entry.is_dir {
+                let indent = format!("{}{}", indent, if last { "    " } else { "│   " });
+                children(entries, &entry.path, &indent);
+            }
+        }
+    }
+
+    entries.sort_by(|a, b| a.path.split('/').cmp(b.path.split('/')));
+    let root = match path.trim_end_matches('/') {
+        "" if path.starts_with('/') => "/",
+        root => root,
+    };
+    println!("{}", root);
+    children(&entries, root, "");
+
+    let dirs = entries.iter().filter(|e| e.is_dir).count();
+    let files = entries.len() - dirs;
+    println!(
+        "\n{} {}, {} {}",
+        dirs,
+        if dirs == 1 {
+            "directory"
+        } else {
+            "directories"
+        },
+        files,
+        if files == 1 { "file" } else { "files" }
+    );
+}
+
+/// Unix time as `YYYY-MM-DD HH:MM` in UTC
+fn format_time(secs: u64) -> String {
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        secs % 86400 / 3600,
+        secs % 3600 / 60
+    )
+}
+
+/// Space on one filesystem of the device, from `os.statvfs` or upyOS's `df`
+struct FsUsage {
+    mount: String,
+    block_size: u64,
+    blocks: u64,
+    free_blocks: u64,
+    /// Free blocks available to unprivileged users
+    avail_blocks: u64,
+}
+
+impl FsUsage {
+    /// Blocks in use. A report with more free than total blocks counts as empty.
+    fn used_blocks(&self) -> u64 {
+        self.blocks.saturating_sub(self.free_blocks)
+    }
+
+    /// Share of the usable space in use, in percent, as `df` computes it
+    fn use_percent(&self) -> u64 {
+        let usable = self.used_blocks() + self.avail_blocks;
+        if usable == 0 {
+            0
+        } else {
+            (self.used_blocks() * 100).div_ceil(usable)
+        }
+    }
+}
+
+/// Reads the table printed by a shell `df`: a header naming the size, used
+/// and available columns, then one row per filesystem ending with its mount
+/// point. Sizes count the blocks named in the header (`1K-blocks`) or are
+/// bytes, optionally with a K, M or G suffix.
+fn parse_df(output: &str) -> Result<Vec<FsUsage>> {
+    let unexpected = || anyhow::anyhow!("Unexpected df output:\n{}", output);
+    let mut lines = output.lines().map(str::trim).filter(|l| !l.is_empty());
+    let header: Vec<String> = lines
+        .by_ref()
+        .find(|l| {
+            let l = l.to_lowercase();
+            l.contains("used") && (l.contains("avail") || l.contains("free"))
+        })
+        .ok_or_else(unexpected)?
+        .split_whitespace()
+        .map(str::to_lowercase)
+        .collect();
+    let column = |names: &[&str]| {
+        header
+            .iter()
+            .position(|h| names.contains(&h.as_str()) || names.iter().any(|n| h.ends_with(n)))
+    };
+    let size_col = column(&["size", "total", "-blocks"]).ok_or_else(unexpected)?;
+    let used_col = column(&["used"]).ok_or_else(unexpected)?;
+    let avail_col = column(&["avail", "available", "free"]).ok_or_else(unexpected)?;
+    let block_size = header[size_col]
+        .strip_suffix("-blocks")
+        .map(|unit| parse_size(unit, 1))
+        .unwrap_or(Some(1))
+        .ok_or_else(unexpected)?;
+
+    lines
+        .map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let value = |i: usize| fields.get(i).and_then(|f| parse_size(f, block_size));
+            match (value(size_col), value(used_col), value(avail_col)) {
+                (Some(total), Some(used), Some(avail))
+                    if fields.len() > size_col.max(used_col).max(avail_col) + 1 =>
+                {
+                    Ok(FsUsage {
+                        mount: fields[fields.len() - 1].to_string(),
+                        block_size,
+                        blocks: total / block_size,
+                        free_blocks: total.saturating_sub(used) / block_size,
+                        avail_blocks: avail / block_size,
+                    })
+                }
+                _ => Err(unexpected()),
+            }
+        })
+        .collect()
+}
+
+/// Bytes in a `df` size: a count of `unit` bytes, or with a K, M or G suffix
+fn parse_size(text: &str, unit: u64) -> Option<u64> {
+    let text = text.trim_end_matches(['B', 'b', 'i']);
+    let (number, scale) = match text.char_indices().last()? {
+        (i, 'k' | 'K') => (&text[..i], 1u64 << 10),
+        (i, 'm' | 'M') => (&text[..i], 1 << 20),
+        (i, 'g' | 'G') => (&text[..i], 1 << 30),
+        _ => (text, unit),
+    };
+    if let Ok(count) = number.parse::<u64>() {
+        return count.checked_mul(scale);
+    }
+    let value = number.parse::<f64>().ok().filter(|v| *v >= 0.0)?;
+    Some((value * scale as f64).round() as u64)
+}
+
+/// Prints `df` output as a table or as JSON
+fn print_filesystems(filesystems: &[FsUsage], json: bool) {
+    if json {
+        let items: Vec<String> = filesystems
+            .iter()
+            .map(|fs| {
+                format!(
+                    "{{\"mount\": {}, \"block_size\": {}, \"blocks_total\": {}, \"blocks_used\": {}, \"blocks_free\": {}, \"bytes_total\": {}, \"bytes_used\": {}, \"bytes_free\": {}, \"bytes_avail\": {}, \"use_percent\": {}}}",
+                    json_string(&fs.mount),
+                    fs.block_size,
+                    fs.blocks,
+                    fs.used_blocks(),
+                    fs.free_blocks,
+                    fs.blocks * fs.block_size,
+                    fs.used_blocks() * fs.block_size,
+                    fs.free_blocks * fs.block_size,
+                    fs.avail_blocks * fs.block_size,
+                    fs.use_percent()
+                )
+            })
+            .collect();
+        println!("[{}]", items.join(", "));
+        return;
+    }
+
+    println!(
+        "{:<12} {:>6} {:>8} {:>8} {:>8} {:>10} {:>10} {:>10} {:>5}",
+        "Mounted on", "Block", "Blocks", "Used", "Free", "Size", "Used", "Avail", "Use%"
+    );
+    for fs in filesystems {
+        println!(
+            "{:<12} {:>6} {:>8} {:>8} {:>8} {:>10} {:>10} {:>10} {:>4}%",
+            fs.mount,
+            fs.block_size,
+            fs.blocks,
+            fs.used_blocks(),
+            fs.free_blocks,
+            progress::human_bytes((fs.blocks * fs.block_size) as f64),
+            progress::human_bytes((fs.used_blocks() * fs.block_size) as f64),
+            progress::human_bytes((fs.avail_blocks * fs.block_size) as f64),
+            fs.use_percent()
+        );
+    }
+}
+
+/// JSON string literal
+fn json_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Total size of `files`, relative to `dir`
+fn local_size(dir: &Path, files: &[String]) -> Result<usize> {
+    files.iter().try_fold(0, |total, file| {
+        let path = dir.join(file);
+        let size = std::fs::metadata(&path)
+            .with_context(|| format!("Could not read {}", path.display()))?
+            .len();
+        Ok(total + size as usize)
+    })
+}
+
+/// What `sync` does, with paths on the device except for uploads, which
+/// are relative to both directories
+#[derive(Default)]
+struct SyncPlan {
+    mkdirs: Vec<String>,
+    uploads: Vec<(String, &'static str)>,
+    delete_files: Vec<String>,
+    delete_dirs: Vec<String>,
+    unchanged: usize,
+}
+
+/// Modification time of a local file in seconds since the Unix epoch
+fn local_mtime(path: &Path) -> Result<u64> {
+    let modified = std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .with_context(|| format!("Could not read {}", path.display()))?;
+    Ok(modified
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs()))
+}
+
+/// Directories and files below `dir` that pass `filter`, as sorted paths
+/// relative to `dir` with `/` separators. Directories come before their
+/// contents; empty ones are kept unless there are include patterns.
+fn scan_local_tree(dir: &Path, filter: &PathFilter) -> Result<(Vec<String>, Vec<String>)> {
+    fn walk(
+        dir: &Path,
+        prefix: &str,
+        filter: &PathFilter,
+        dirs: &mut Vec<String>,
+        files: &mut Vec<String>,
+    ) -> Result<()> {
+        let mut entries = std::fs::read_dir(dir)
+            .with_context(|| format!("Could not read directory {}", dir.display()))?
+            .collect::<io::Result<Vec<_>>>()
+            .with_context(|| format!("Could not read directory {}", dir.display()))?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let path = format!("{}{}", prefix, name);
+            if entry.path().is_dir() {
+                if !filter.excludes(&path) {
+                    dirs.push(path.clone());
+                    walk(&entry.path(), &format!("{}/", path), filter, dirs, files)?;
+                }
+            } else if filter.includes_file(&path) {
+                files.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    if !dir.is_dir() {
+        anyhow::bail!("{} is not a directory", dir.display());
+    }
+    let mut dirs = Vec::new();
+    let mut files = Vec::new();
+    walk(dir, "", filter, &mut dirs, &mut files)?;
+    if filter.has_includes() {
+        // Only the directories leading to selected files
+        dirs.retain(|d| files.iter().any(|f| f.starts_with(&format!("{}/", d))));
+    }
+    Ok((dirs, files))
+}
+
+/// Joins a relative path to a directory on the device
+fn join_remote(dir: &str, path: &str) -> String {
+    match dir.trim_end_matches('/') {
+        "" if dir.starts_with('/') => format!("/{}", path),
+        "" => path.to_string(),
+        dir => format!("{}/{}", dir, path),
+    }
+}
+
+/// `dir` and each directory above it, outermost first, without the root
+fn remote_ancestors(dir: &str) -> Vec<String> {
+    let mut ancestors = Vec::new();
+    let mut path = if dir.starts_with('/') {
+        "/".to_string()
+    } else {
+        String::new()
+    };
+    for part in dir.split('/').filter(|p| !p.is_empty() && *p != ".") {
+        path = join_remote(&path, part);
+        ancestors.push(path.clone());
+    }
+    ancestors
+}
+
+/// Python helpers for the filesystem commands. Failures the user should see
+/// are raised as `E` and reported by `run_fs_script` without a traceback.
+const FS_HELPERS: &str = r#"import os
+class E(Exception):
+    pass
+def k(p):
+    try:
+        return 2 if os.stat(p)[0] & 0x4000 else 1
+    except OSError:
+        return 0
+def need(p, want):
+    t = k(p)
+    if t == 0:
+        raise E('No such file or directory: ' + p)
+    if want == 1 and t == 2:
+        raise E("'%s' is a directory" % p)
+    if want == 2 and t == 1:
+        raise E("'%s' is not a directory" % p)
+    return t
+def sub(d, n):
+    return (d if d.endswith('/') else d + '/') + n
+def parent(p):
+    p = p.rstrip('/')
+    i = p.rfind('/')
+    return '/' if i == 0 else p[:i] if i > 0 else '.'
+def target(s, d):
+    return sub(d, s.rstrip('/').split('/')[-1]) if k(d) == 2 else d
+def mkdirs(ps, exist_ok):
+    for p in ps:
+        t = k(p)
+        if t == 0:
+            if k(parent(p)) != 2:
+                raise E('No such directory: ' + parent(p))
+            os.mkdir(p)
+        elif t == 1 or not exist_ok:
+            raise E("'%s' already exists" % p)
+def rmtree(p):
+    for x in os.ilistdir(p):
+        c = sub(p, x[0])
+        if x[1] & 0x4000:
+            rmtree(c)
+        else:
+            os.remove(c)
+    os.rmdir(p)
+def rm(p, r):
+    if need(p, 0) == 1:
+        os.remove(p)
+    elif r:
+        rmtree(p)
+    else:
+        raise E("'%s' is a directory (use -r)" % p)
+def rmdir(p):
+    need(p, 2)
+    if list(os.ilistdir(p)):
+        raise E('Directory not empty: ' + p)
+    os.rmdir(p)
+def mv(s, d):
+    need(s, 0)
+    os.rename(s, target(s, d))
+def cp(s, d):
+    need(s, 1)
+    with open(s, 'rb') as i, open(target(s, d), 'wb') as o:
+        while True:
+            b = i.read(512)
+            if not b:
+                break
+            o.write(b)
+def touch(p):
+    if k(p) == 0:
+        open(p, 'ab').close()"#;
+
+/// Device path from a command-line argument; a leading `:` marks a path on
+/// the device and is optional
+fn remote_arg(arg: &str) -> &str {
+    arg.strip_prefix(':').unwrap_or(arg)
+}
+
+fn remote_args(args: &[String]) -> Vec<String> {
+    args.iter().map(|arg| remote_arg(arg).to_string()).collect()
+}
+
+/// Checks downloaded data against the size and hex SHA-256 reported by the
+/// device. An empty hash means the device has no `hashlib.sha256`.
+fn verify_download(content: &[u8], size: usize, device_hash: &str) -> Result<()> {
+    if content.len() != size {
+        anyhow::bail!(
+            "Size mismatch: received {} bytes, the device reported {}",
+            content.len(),
+            size
+        );
+    }
+    if device_hash.is_empty() {
+        println!("[INFO] hashlib.sha256 not available on device, only the size was verified");
+    } else if device_hash != sha256::hex_digest(content) {
+        anyhow::bail!("SHA-256 mismatch: the received data differs from the device's file");
+    }
+    Ok(())
+}
+
 // Simple base64 implementation
 fn base64_encode(data: &[u8]) -> String {
     const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
-    let mut result = String::with_capacity((data.len() + 2) / 3 * 4);
+    let mut result = String::with_capacity(data.len().div_ceil(3) * 4);
 
     for chunk in data.chunks(3) {
         let b = match chunk.len() {
@@ -1119,83 +3370,507 @@ fn base64_char_value(c: char) -> u8 {
     }
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
-
-    match cli.command {
-        Commands::Connect { port, baud } => {
-            let port = resolve_port(port);
-            let mut device = MpDevice::new(&port, baud)?;
-            device.run_repl()?;
-        }
-        Commands::Ls { port, path } => {
-            let port = resolve_port(port);
-            let mut device = MpDevice::new(&port, 115200)?;
-            let files = device.list_files(&path)?;
-            println!("Files in '{}'", path);
-            for file in files {
-                println!("  {}", file);
-            }
-        }
-        Commands::Put { port, source, dest } => {
-            let port = resolve_port(port);
-            let mut device = MpDevice::new(&port, 115200)?;
-            let remote_path = dest.unwrap_or_else(|| {
-                source
+/// Number of Ctrl-C presses caught while code runs on the device
+static INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+/// Whether Ctrl-C is being forwarded; otherwise it exits like the default handler
+static FORWARDING: AtomicBool = AtomicBool::new(false);
+
+/// Catches Ctrl-C so it can be forwarded to the device instead of killing
+/// upyremote, until the returned guard is dropped. A third press exits
+/// immediately in case the device hangs.
+fn install_interrupt_handler() -> InterruptGuard {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let _ = ctrlc::set_handler(|| {
+            if !FORWARDING.load(Ordering::SeqCst) || INTERRUPTS.fetch_add(1, Ordering::SeqCst) >= 2
+            {
+                let _ = disable_raw_mode();
+                std::process::exit(130);
+            }
+        });
+    });
+    FORWARDING.store(true, Ordering::SeqCst);
+    InterruptGuard
+}
+
+/// Restores the default Ctrl-C behaviour when dropped
+#[must_use]
+struct InterruptGuard;
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        FORWARDING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Code run by `exec`, `run` or `mount` raised an exception on the device.
+/// Its traceback has been printed already.
+#[derive(Debug)]
+struct DeviceException;
+
+impl std::fmt::Display for DeviceException {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Device raised an exception")
+    }
+}
+
+impl std::error::Error for DeviceException {}
+
+/// Prints the output of `exec`/`run`; a device-side exception prints its
+/// traceback on stderr and fails with `DeviceException`
+fn report_exec_result(result: &ExecResult) -> Result<()> {
+    print!("{}", result.stdout);
+    let _ = io::stdout().flush();
+    if result.traceback.is_some() {
+        eprintln!("{}", result.stderr.trim_end());
+        return Err(DeviceException.into());
+    }
+    Ok(())
+}
+
+/// Settings shared by the commands of one invocation, and the device they
+/// work on. Commands chained with `+` reuse the open device, so the port is
+/// opened and the mode detected only once.
+struct Session {
+    config: Config,
+    profile: Profile,
+    baud: Baud,
+    show_progress: bool,
+    /// Open device with the port name it was resolved to
+    device: Option<(String, MpDevice)>,
+}
+
+impl Session {
+    /// The device at `port`. The open device is reused when no port is
+    /// given or it is the same one.
+    fn device(&mut self, port: Option<String>) -> Result<&mut MpDevice> {
+        if port.is_some() || self.device.is_none() {
+            let port = resolve_port(port, &self.config, &self.profile)?;
+            if self.device.as_ref().is_none_or(|(open, _)| *open != port) {
+                // Close the previous port first
+                self.device = None;
+                let mut device = MpDevice::new(&port, self.baud, &self.profile)?;
+                device.show_progress = self.show_progress;
+                self.device = Some((port, device));
+            }
+        }
+        Ok(&mut self.device.as_mut().expect("device was just opened").1)
+    }
+
+    fn default_timeout(&self, timeout: Option<u64>) -> Option<Duration> {
+        timeout.or(self.profile.timeout).map(Duration::from_secs)
+    }
+
+    /// Runs the commands of a script file. With `on-error continue` a failing
+    /// command is reported and the script goes on.
+    fn run_script_file(&mut self, port: Option<String>, file: &Path, set: &[String]) -> Result<()> {
+        let text = std::fs::read_to_string(file)
+            .with_context(|| format!("Could not read {}", file.display()))?;
+        let name = file.display().to_string();
+        let steps = script::parse(&text, &name, &mut script::Vars::new(set)?)?;
+        if port.is_some() {
+            self.device(port)?;
+        }
+
+        for step in steps {
+            let at = format!("{}:{}: {}", name, step.line, step.text);
+            match self.run(step.command) {
+                Ok(()) => {}
+                Err(e) if step.on_error == script::OnError::Continue => {
+                    eprintln!("[WARNING] {} failed, continuing: {:#}", at, e);
+                }
+                Err(e) => return Err(e.context(format!("{} failed", at))),
+            }
+        }
+        Ok(())
+    }
+
+    fn run(&mut self, command: Commands) -> Result<()> {
+        match command {
+            Commands::Connect { port } => {
+                let device = self.device(port)?;
+                device.run_repl()?;
+            }
+            Commands::Ls {
+                port,
+                path,
+                long,
+                recursive,
+            } => {
+                let path = remote_arg(&path);
+                let device = self.device(port)?;
+                let entries = device.list_entries(path, recursive)?;
+                print_listing(path, entries, long, recursive);
+            }
+            Commands::Tree { port, path } => {
+                let path = remote_arg(&path);
+                let device = self.device(port)?;
+                let entries = device.list_entries(path, true)?;
+                print_tree(path, entries);
+            }
+            Commands::Df { port, json } => {
+                let device = self.device(port)?;
+                let filesystems = device.filesystems()?;
+                print_filesystems(&filesystems, json);
+            }
+            Commands::Mkdir {
+                port,
+                paths,
+                parents,
+            } => {
+                let device = self.device(port)?;
+                device.mkdir(&remote_args(&paths), parents)?;
+            }
+            Commands::Rm {
+                port,
+                paths,
+                recursive,
+            } => {
+                let device = self.device(port)?;
+                device.remove(&remote_args(&paths), recursive)?;
+            }
+            Commands::Rmdir { port, paths } => {
+                let device = self.device(port)?;
+                device.rmdir(&remote_args(&paths))?;
+            }
+            Commands::Mv { port, source, dest } => {
+                let device = self.device(port)?;
+                device.rename(remote_arg(&source), remote_arg(&dest))?;
+            }
+            Commands::Cp { port, source, dest } => {
+                let device = self.device(port)?;
+                device.copy(remote_arg(&source), remote_arg(&dest))?;
+            }
+            Commands::Touch { port, paths } => {
+                let device = self.device(port)?;
+                device.touch(&remote_args(&paths))?;
+            }
+            Commands::Cat { port, paths } => {
+                let device = self.device(port)?;
+                device.cat(&remote_args(&paths))?;
+            }
+            Commands::Put {
+                port,
+                source,
+                dest,
+                recursive,
+                include,
+                exclude,
+            } => {
+                if source.is_dir() && !recursive {
+                    anyhow::bail!(
+                        "'{}' is a directory (use -r to upload it recursively)",
+                        source.display()
+                    );
+                }
+                let device = self.device(port)?;
+                let name = source.file_name().and_then(|n| n.to_str());
+                if recursive {
+                    let remote_dir = dest.as_deref().map_or(name.unwrap_or("/"), remote_arg);
+                    let filter = PathFilter::new(include, exclude);
+                    device.put_tree(&source, remote_dir, &filter)?;
+                } else {
+                    let remote_path = dest
+                        .as_deref()
+                        .map_or(name.unwrap_or("file.py"), remote_arg);
+                    device.put_file(&source, remote_path)?;
+                }
+            }
+            Commands::Get {
+                port,
+                source,
+                dest,
+                recursive,
+            } => {
+                let source = remote_arg(&source);
+                let name = PathBuf::from(source)
                     .file_name()
                     .and_then(|n| n.to_str())
-                    .unwrap_or("file.py")
-                    .to_string()
-            });
-            device.put_file(&source, &remote_path)?;
-        }
-        Commands::Get { port, source, dest } => {
-            let port = resolve_port(port);
-            let mut device = MpDevice::new(&port, 115200)?;
-            let local_path = dest.unwrap_or_else(|| {
-                PathBuf::from(
-                    PathBuf::from(&source)
-                        .file_name()
-                        .and_then(|n| n.to_str())
-                        .unwrap_or("download.py"),
-                )
-            });
-            device.get_file(&source, &local_path)?;
-        }
-        Commands::Exec { port, command } => {
-            let port = resolve_port(port);
-            let mut device = MpDevice::new(&port, 115200)?;
-            let output = device.exec_command(&command)?;
-            print!("{}", output);
-        }
-        Commands::Reset { port, hard } => {
-            let port = resolve_port(port);
-            let mut device = MpDevice::new(&port, 115200)?;
-            if hard {
-                device.hard_reset()?;
-            } else {
-                device.soft_reset()?;
+                    .map(str::to_string);
+                if recursive && dest.is_none() && name.is_none() {
+                    anyhow::bail!("Give a local directory to download '{}' into", source);
+                }
+                let device = self.device(port)?;
+                if recursive {
+                    let local_dir = dest.unwrap_or_else(|| PathBuf::from(name.unwrap_or_default()));
+                    device.get_tree(source, &local_dir)?;
+                } else {
+                    let local_path = dest
+                        .unwrap_or_else(|| PathBuf::from(name.as_deref().unwrap_or("download.py")));
+                    device.get_file(source, &local_path)?;
+                }
+            }
+            Commands::Sync {
+                port,
+                source,
+                dest,
+                delete,
+                dry_run,
+                include,
+                exclude,
+            } => {
+                let device = self.device(port)?;
+                let remote_dir = dest.as_deref().map_or("/", remote_arg);
+                let filter = PathFilter::new(include, exclude);
+                device.sync(&source, remote_dir, &filter, delete, dry_run)?;
+            }
+            Commands::Exec {
+                port,
+                command,
+                timeout,
+            } => {
+                let timeout = self.default_timeout(timeout);
+                let device = self.device(port)?;
+                let result = device.exec_streaming(&command, timeout)?;
+                report_exec_result(&result)?;
+            }
+            Commands::Reset { port, hard } => {
+                let method = if hard {
+                    ResetMethod::Hard
+                } else {
+                    self.profile.reset.unwrap_or(ResetMethod::Soft)
+                };
+                let device = self.device(port)?;
+                match method {
+                    ResetMethod::Soft => device.soft_reset()?,
+                    ResetMethod::Hard => device.hard_reset()?,
+                    ResetMethod::Machine => device.machine_reset()?,
+                }
+                // The board restarts; a chained command reconnects to it
+                if !matches!(method, ResetMethod::Soft) {
+                    self.device = None;
+                }
+            }
+            Commands::Run {
+                port,
+                file,
+                timeout,
+            } => {
+                let content = std::fs::read_to_string(&file)
+                    .with_context(|| format!("Could not read {}", file.display()))?;
+                let timeout = self.default_timeout(timeout);
+                let device = self.device(port)?;
+                let result = device.exec_streaming(&content, timeout)?;
+                report_exec_result(&result)?;
+            }
+            Commands::Mount {
+                port,
+                dir,
+                script,
+                timeout,
+            } => {
+                let timeout = self.default_timeout(timeout);
+                let device = self.device(port)?;
+                if let Some(result) = device.mount(&dir, script.as_deref(), timeout)? {
+                    report_exec_result(&result)?;
+                }
+            }
+            Commands::Watch {
+                port,
+                source,
+                dest,
+                run,
+                include,
+                exclude,
+            } => {
+                let device = self.device(port)?;
+                let remote_dir = dest.as_deref().map_or("/", remote_arg);
+                let filter = PathFilter::new(include, exclude);
+                device.watch(&source, remote_dir, &filter, run.as_deref())?;
+            }
+            Commands::Send {
+                port,
+                data,
+                timeout,
+            } => {
+                let timeout = timeout.or(self.profile.timeout);
+                let device = self.device(port)?;
+                let output = device.send_string(&data, timeout)?;
+                print!("{}", output);
+            }
+            Commands::Script { port, file, set } => {
+                self.run_script_file(port, &file, &set)?;
+            }
+            Commands::Devices => {
+                ports::print_devices()?;
+            }
+            Commands::Simulate { mode, root, listen } => {
+                simulator::serve(mode, &root, &listen)?;
             }
         }
-        Commands::Run { port, file } => {
-            let port = resolve_port(port);
-            let mut device = MpDevice::new(&port, 115200)?;
-            let content = std::fs::read_to_string(&file)
-                .with_context(|| format!("Could not read {}", file.display()))?;
-            let output = device.exec_command(&content)?;
-            print!("{}", output);
+
+        Ok(())
+    }
+}
+
+/// Splits the arguments at each lone `+`, one list per command. The first
+/// list starts with the program name. An argument of only `+` signs loses
+/// one of them, so `++` passes a literal `+`.
+fn split_chain(args: impl Iterator<Item = OsString>) -> Vec<Vec<OsString>> {
+    let mut commands = vec![Vec::new()];
+    for arg in args {
+        if arg == "+" {
+            commands.push(Vec::new());
+            continue;
         }
-        Commands::Send {
-            port,
-            data,
-            timeout,
-        } => {
-            let port = resolve_port(port);
-            let mut device = MpDevice::new(&port, 115200)?;
-            let output = device.send_string(&data, timeout)?;
-            print!("{}", output);
+        let arg = match arg.to_str() {
+            Some(text) if text.len() > 1 && text.bytes().all(|b| b == b'+') => {
+                OsString::from(&text[1..])
+            }
+            _ => arg,
+        };
+        commands.last_mut().expect("never empty").push(arg);
+    }
+    commands
+}
+
+fn main() -> Result<()> {
+    let mut commands = split_chain(std::env::args_os()).into_iter();
+    let cli = Cli::parse_from(commands.next().unwrap_or_default());
+    // Parse the whole chain before running any of it
+    let chained: Vec<Commands> = commands
+        .map(|args| ChainedCommand::parse_from(args).command)
+        .collect();
+
+    let config = Config::load()?;
+    let mut profile = config.profile(cli.profile)?;
+    if let Some(chunk_size) = cli.chunk_size {
+        profile.chunk_size = Some(chunk_size as usize);
+    }
+    let baud = cli
+        .baud
+        .or(profile.baud)
+        .unwrap_or(Baud::Rate(DEFAULT_BAUD));
+    let mut session = Session {
+        config,
+        baud,
+        profile,
+        show_progress: !cli.quiet && (cli.progress || atty::is(atty::Stream::Stdout)),
+        device: None,
+    };
+
+    let result = std::iter::once(cli.command)
+        .chain(chained)
+        .try_for_each(|command| session.run(command));
+    match result {
+        // The traceback explains it already
+        Err(e) if e.is::<DeviceException>() && e.chain().count() == 1 => std::process::exit(1),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sim_device(mode: &str, name: &str) -> MpDevice {
+        let root =
+            std::env::temp_dir().join(format!("upyremote-unit-{}-{}", std::process::id(), name));
+        let port = format!("sim:{}:{}", mode, root.display());
+        MpDevice::new(&port, Baud::Rate(DEFAULT_BAUD), &Profile::default()).unwrap()
+    }
+
+    /// Code longer than the simulator's raw-paste window, so flow control is needed
+    fn long_code() -> String {
+        format!("x = {:?}\nprint(len(x))", "a".repeat(1000))
+    }
+
+    fn chain(args: &[&str]) -> Vec<Vec<OsString>> {
+        split_chain(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn splits_chain_at_lone_plus() {
+        assert_eq!(
+            chain(&["upyremote", "put", "a.py", "+", "reset", "+", "connect"]),
+            [
+                vec!["upyremote", "put", "a.py"],
+                vec!["reset"],
+                vec!["connect"]
+            ]
+        );
+        assert_eq!(
+            chain(&["upyremote", "exec", "1 + 2"]),
+            [vec!["upyremote", "exec", "1 + 2"]]
+        );
+    }
+
+    #[test]
+    fn doubled_plus_is_a_literal_argument() {
+        assert_eq!(
+            chain(&["upyremote", "exec", "++", "+", "cat", "+++"]),
+            [vec!["upyremote", "exec", "+"], vec!["cat", "++"]]
+        );
+    }
+
+    #[test]
+    fn parses_df_with_block_counts() {
+        let filesystems = parse_df(
+            "Filesystem     1K-blocks  Used Available Use% Mounted on\n\
+             flash               2048   152      1896   8% /\n\
+             sd                 30000 10000     20000  34% /sd\n",
+        )
+        .unwrap();
+        assert_eq!(filesystems.len(), 2);
+        let flash = &filesystems[0];
+        assert_eq!((flash.mount.as_str(), flash.block_size), ("/", 1024));
+        assert_eq!(
+            (flash.blocks, flash.free_blocks, flash.avail_blocks),
+            (2048, 1896, 1896)
+        );
+        assert_eq!(filesystems[1].mount, "/sd");
+    }
+
+    #[test]
+    fn parses_df_with_human_sizes() {
+        let filesystems =
+            parse_df("Size  Used  Avail  Use%  Mounted on\n2.0M  512K  1.5M  25%  /\n").unwrap();
+        let root = &filesystems[0];
+        assert_eq!(root.block_size, 1);
+        assert_eq!(root.blocks, 2 << 20);
+        assert_eq!(root.used_blocks(), 512 << 10);
+        assert_eq!(root.avail_blocks, 3 << 19);
+    }
+
+    #[test]
+    fn rejects_unexpected_df_output() {
+        assert!(parse_df("df: command not found").is_err());
+        assert!(parse_df("Size Used Avail Use% Mounted on\nsoon\n").is_err());
+    }
+
+    #[test]
+    fn odd_filesystem_reports_count_as_empty() {
+        let fs = FsUsage {
+            mount: "/".to_string(),
+            block_size: 4096,
+            blocks: 10,
+            free_blocks: 12,
+            avail_blocks: 12,
+        };
+        assert_eq!(fs.used_blocks(), 0);
+        assert_eq!(fs.use_percent(), 0);
+    }
+
+    #[test]
+    fn code_is_sent_with_raw_paste() {
+        if simulator::python3_missing() {
+            return;
         }
+        let mut device = sim_device("repl", "raw-paste");
+        let result = device.exec_with(&long_code(), None, None).unwrap();
+        assert_eq!(result.stdout.trim(), "1000");
+        // Cleared when the device refuses raw-paste mode
+        assert!(device.raw_paste);
     }
 
-    Ok(())
+    #[test]
+    fn code_falls_back_without_raw_paste() {
+        if simulator::python3_missing() {
+            return;
+        }
+        let mut device = sim_device("repl-legacy", "raw-paste-legacy");
+        let result = device.exec_with(&long_code(), None, None).unwrap();
+        assert_eq!(result.stdout.trim(), "1000");
+        assert!(!device.raw_paste);
+    }
 }
@@ -0,0 +1,507 @@
+//! Host side of `mount`: a local directory served to the device over the
+//! serial link.
+//!
+//! The device runs `shim()`, which mounts a VFS at `MOUNT_POINT` that turns
+//! each filesystem call into a request: `MOUNT_REQUEST`, a command byte and
+//! its arguments. upyremote spots the request in the device output, answers
+//! it from the local directory and writes the reply to the device's stdin.
+//! Integers are 32-bit little-endian, strings and data a length followed by
+//! the bytes. Every reply starts with a status, a negative errno on failure.
+
+use anyhow::Result;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use crate::quote::py_str;
+
+/// Byte that starts a request in the device output (Ctrl-X)
+pub const MOUNT_REQUEST: u8 = 0x18;
+/// Where the local directory appears on the device
+pub const MOUNT_POINT: &str = "/remote";
+/// Most data a single request carries or asks for; the shim reads and
+/// writes in chunks of this size
+const MAX_DATA: usize = 4096;
+
+/// Device-side VFS. Requests are made with Ctrl-C disabled, so reply data
+/// can't interrupt the running code.
+pub fn shim() -> String {
+    format!(
+        r#"import io, os, sys, struct, time, micropython
+_o = 946684800 if time.gmtime(0)[0] == 2000 else 0
+
+def _rd(n):
+    b = b''
+    while len(b) < n:
+        b += sys.stdin.buffer.read(n - len(b))
+    return b
+
+def _ri():
+    return struct.unpack('<i', _rd(4))[0]
+
+def _rs():
+    return _rd(_ri()).decode()
+
+def _rq(c, a, f=None):
+    micropython.kbd_intr(-1)
+    try:
+        w = sys.stdout.buffer.write
+        w(b'\x18' + c)
+        for x in a:
+            if isinstance(x, int):
+                w(struct.pack('<i', x))
+            else:
+                if isinstance(x, str):
+                    x = x.encode()
+                w(struct.pack('<i', len(x)))
+                w(x)
+        n = _ri()
+        if n < 0:
+            raise OSError(-n)
+        return f(n) if f else n
+    finally:
+        micropython.kbd_intr(3)
+
+class RemoteFile(io.IOBase):
+    def __init__(s, fd, b):
+        s.fd = fd
+        s.b = b
+    def _d(s, d):
+        return d if s.b else d.decode()
+    def ioctl(s, req, arg):
+        if req == 4:
+            s.close()
+        return 0
+    def readinto(s, buf):
+        d = _rq(b'R', (s.fd, len(buf)), _rd)
+        buf[:len(d)] = d
+        return len(d)
+    def read(s, n=-1):
+        d = b''
+        while n < 0 or len(d) < n:
+            c = _rq(b'R', (s.fd, 4096 if n < 0 else min(n - len(d), 4096)), _rd)
+            if not c:
+                break
+            d += c
+        return s._d(d)
+    def readline(s):
+        return s._d(_rq(b'I', (s.fd,), _rd))
+    def readlines(s):
+        return list(s)
+    def __iter__(s):
+        return s
+    def __next__(s):
+        l = s.readline()
+        if not l:
+            raise StopIteration
+        return l
+    def write(s, d):
+        if isinstance(d, str):
+            d = d.encode()
+        n = 0
+        while n < len(d):
+            n += _rq(b'W', (s.fd, d[n:n + 4096]))
+        return n
+    def flush(s):
+        pass
+    def close(s):
+        if s.fd >= 0:
+            _rq(b'C', (s.fd,))
+            s.fd = -1
+    def __enter__(s):
+        return s
+    def __exit__(s, *a):
+        s.close()
+
+class RemoteFS:
+    def __init__(s):
+        s.cwd = '/'
+    def mount(s, readonly, mkfs):
+        pass
+    def umount(s):
+        pass
+    def _p(s, p):
+        return p if p.startswith('/') else s.cwd + p
+    def chdir(s, p):
+        p = s._p(p).rstrip('/') + '/'
+        if not s.stat(p)[0] & 0x4000:
+            raise OSError(20)
+        s.cwd = p
+    def getcwd(s):
+        return s.cwd[:-1] or '/'
+    def stat(s, p):
+        def f(n):
+            m, z, t = _ri(), _ri(), _ri() - _o
+            return (m, 0, 0, 0, 0, 0, z, t, t, t)
+        return _rq(b'S', (s._p(p),), f)
+    def ilistdir(s, p):
+        return iter(_rq(b'L', (s._p(p),), lambda n: [(_rs(), _ri(), 0, _ri()) for i in range(n)]))
+    def open(s, p, m):
+        return RemoteFile(_rq(b'O', (s._p(p), m)), 'b' in m)
+    def mkdir(s, p):
+        _rq(b'M', (s._p(p),))
+    def remove(s, p):
+        _rq(b'D', (s._p(p),))
+    def rmdir(s, p):
+        _rq(b'X', (s._p(p),))
+    def rename(s, a, b):
+        _rq(b'N', (s._p(a), s._p(b)))
+
+try:
+    os.umount({mp})
+except OSError:
+    pass
+os.mount(RemoteFS(), {mp})
+os.chdir({mp})"#,
+        mp = py_str(MOUNT_POINT)
+    )
+}
+
+/// Leaves the mounted directory, run when upyremote stops serving it
+pub fn unmount() -> String {
+    format!(
+        "import os\nos.chdir('/')\nos.umount({})",
+        py_str(MOUNT_POINT)
+    )
+}
+
+// MicroPython's errno values
+const ENOENT: i32 = 2;
+const EIO: i32 = 5;
+const EBADF: i32 = 9;
+const EACCES: i32 = 13;
+const EEXIST: i32 = 17;
+const ENOTDIR: i32 = 20;
+const EISDIR: i32 = 21;
+const EINVAL: i32 = 22;
+const ENOTEMPTY: i32 = 39;
+
+/// Answers the requests of a mounted directory
+pub struct MountServer {
+    root: PathBuf,
+    /// Open files by descriptor; closed ones leave a `None`
+    files: Vec<Option<File>>,
+}
+
+/// Reads the next `n` bytes of a request from the device
+pub type RequestReader<'a> = &'a mut dyn FnMut(usize) -> Result<Vec<u8>>;
+
+impl MountServer {
+    pub fn new(root: &Path) -> Self {
+        MountServer {
+            root: root.canonicalize().unwrap_or_else(|_| root.to_path_buf()),
+            files: Vec::new(),
+        }
+    }
+
+    /// Reads the arguments of request `command` and returns the reply
+    pub fn handle(&mut self, command: u8, read: RequestReader) -> Result<Vec<u8>> {
+        let mut reply = Vec::new();
+        let status = match command {
+            b'S' => {
+                let path = read_string(read)?;
+                self.stat(&path, &mut reply)
+            }
+            b'L' => {
+                let path = read_string(read)?;
+                self.list_dir(&path, &mut reply)
+            }
+            b'O' => {
+                let path = read_string(read)?;
+                let mode = read_string(read)?;
+                self.open(&path, &mode)
+            }
+            b'R' => {
+                let fd = read_int(read)?;
+                let len = read_int(read)?;
+                self.read(fd, len, &mut reply)
+            }
+            b'I' => {
+                let fd = read_int(read)?;
+                self.read_line(fd, &mut reply)
+            }
+            b'W' => {
+                let fd = read_int(read)?;
+                let data = read_bytes(read)?;
+                self.write(fd, &data)
+            }
+            b'C' => {
+                let fd = read_int(read)?;
+                match self.files.get_mut(fd as usize).and_then(Option::take) {
+                    Some(_) => Ok(0),
+                    None => Err(EBADF),
+                }
+            }
+            b'M' => {
+                let path = read_string(read)?;
+                self.local(&path)
+                    .and_then(|p| errno(fs::create_dir(p)).map(|()| 0))
+            }
+            b'D' => {
+                let path = read_string(read)?;
+                self.local(&path)
+                    .and_then(|p| errno(fs::remove_file(p)).map(|()| 0))
+            }
+            b'X' => {
+                let path = read_string(read)?;
+                self.local(&path)
+                    .and_then(|p| errno(fs::remove_dir(p)).map(|()| 0))
+            }
+            b'N' => {
+                let from = read_string(read)?;
+                let to = read_string(read)?;
+                self.local(&from)
+                    .and_then(|from| Ok((from, self.local(&to)?)))
+                    .and_then(|(from, to)| errno(fs::rename(from, to)))
+                    .map(|()| 0)
+            }
+            other => anyhow::bail!("Unknown mount request 0x{:02x}", other),
+        };
+
+        Ok(match status {
+            Ok(status) => {
+                let mut full = status.to_le_bytes().to_vec();
+                full.extend(reply);
+                full
+            }
+            Err(code) => (-code).to_le_bytes().to_vec(),
+        })
+    }
+
+    /// Local path for a path on the mount, which must stay inside the directory
+    fn local(&self, path: &str) -> Result<PathBuf, i32> {
+        let mut parts: Vec<&str> = Vec::new();
+        for part in path.split('/') {
+            match part {
+                "" | "." => {}
+                ".." => {
+                    parts.pop().ok_or(EACCES)?;
+                }
+                part => parts.push(part),
+            }
+        }
+        let path = parts.iter().fold(self.root.clone(), |p, part| p.join(part));
+
+        // Symlinks can lead anywhere, so check where the path really ends up
+        let real = match path.canonicalize() {
+            Ok(real) => real,
+            // A dangling symlink would be followed when created
+            Err(_) if fs::symlink_metadata(&path).is_ok() => return Err(EACCES),
+            // Not there yet, but its directory has to be
+            Err(_) => {
+                let name = path.file_name().ok_or(EACCES)?;
+                errno(path.parent().ok_or(EACCES)?.canonicalize())?.join(name)
+            }
+        };
+        if real.starts_with(&self.root) {
+            Ok(real)
+        } else {
+            Err(EACCES)
+        }
+    }
+
+    fn stat(&self, path: &str, reply: &mut Vec<u8>) -> Result<i32, i32> {
+        let meta = errno(fs::metadata(self.local(path)?))?;
+        push_int(reply, if meta.is_dir() { 0x4000 } else { 0x8000 });
+        push_int(reply, if meta.is_dir() { 0 } else { meta.len() as i32 });
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs() as i32);
+        push_int(reply, mtime);
+        Ok(0)
+    }
+
+    fn list_dir(&self, path: &str, reply: &mut Vec<u8>) -> Result<i32, i32> {
+        let mut entries = Vec::new();
+        for entry in errno(fs::read_dir(self.local(path)?))? {
+            let entry = errno(entry)?;
+            let meta = errno(entry.metadata())?;
+            entries.push((entry.file_name().to_string_lossy().to_string(), meta));
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        for (name, meta) in &entries {
+            push_bytes(reply, name.as_bytes());
+            push_int(reply, if meta.is_dir() { 0x4000 } else { 0x8000 });
+            push_int(reply, if meta.is_dir() { 0 } else { meta.len() as i32 });
+        }
+        Ok(entries.len() as i32)
+    }
+
+    fn open(&mut self, path: &str, mode: &str) -> Result<i32, i32> {
+        let path = self.local(path)?;
+        let mut options = OpenOptions::new();
+        match mode.chars().find(|c| "rwax".contains(*c)).unwrap_or('r') {
+            'r' => options.read(true).write(mode.contains('+')),
+            'w' => options.write(true).create(true).truncate(true),
+            'a' => options.append(true).create(true),
+            _ => options.write(true).create_new(true),
+        };
+        if mode.contains('+') {
+            options.read(true);
+        }
+        let file = errno(options.open(&path))?;
+        if file.metadata().is_ok_and(|m| m.is_dir()) {
+            return Err(EISDIR);
+        }
+        // Reuse the lowest free descriptor
+        let fd = match self.files.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.files[fd] = Some(file);
+                fd
+            }
+            None => {
+                self.files.push(Some(file));
+                self.files.len() - 1
+            }
+        };
+        Ok(fd as i32)
+    }
+
+    fn file(&mut self, fd: i32) -> Result<&mut File, i32> {
+        self.files
+            .get_mut(usize::try_from(fd).map_err(|_| EBADF)?)
+            .and_then(Option::as_mut)
+            .ok_or(EBADF)
+    }
+
+    fn read(&mut self, fd: i32, len: i32, reply: &mut Vec<u8>) -> Result<i32, i32> {
+        let len = usize::try_from(len).map_err(|_| EINVAL)?.min(MAX_DATA);
+        let mut data = Vec::with_capacity(len);
+        errno(self.file(fd)?.take(len as u64).read_to_end(&mut data))?;
+        reply.extend_from_slice(&data);
+        Ok(data.len() as i32)
+    }
+
+    fn read_line(&mut self, fd: i32, reply: &mut Vec<u8>) -> Result<i32, i32> {
+        let file = self.file(fd)?;
+        let mut byte = [0u8; 1];
+        while errno(file.read(&mut byte))? == 1 {
+            reply.push(byte[0]);
+            if byte[0] == b'\n' {
+                break;
+            }
+        }
+        Ok(reply.len() as i32)
+    }
+
+    fn write(&mut self, fd: i32, data: &[u8]) -> Result<i32, i32> {
+        errno(self.file(fd)?.write_all(data))?;
+        Ok(data.len() as i32)
+    }
+}
+
+/// The errno the device should see for a failed local operation
+fn errno<T>(result: io::Result<T>) -> Result<T, i32> {
+    result.map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        io::ErrorKind::PermissionDenied => EACCES,
+        io::ErrorKind::AlreadyExists => EEXIST,
+        io::ErrorKind::NotADirectory => ENOTDIR,
+        io::ErrorKind::IsADirectory => EISDIR,
+        io::ErrorKind::DirectoryNotEmpty => ENOTEMPTY,
+        io::ErrorKind::InvalidInput => EINVAL,
+        _ => EIO,
+    })
+}
+
+fn read_int(read: RequestReader) -> Result<i32> {
+    let bytes = read(4)?;
+    Ok(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_bytes(read: RequestReader) -> Result<Vec<u8>> {
+    let len = read_int(read)?;
+    match usize::try_from(len) {
+        Ok(len) if len <= MAX_DATA => read(len),
+        _ => anyhow::bail!("Bad mount request length {}", len),
+    }
+}
+
+fn read_string(read: RequestReader) -> Result<String> {
+    Ok(String::from_utf8_lossy(&read_bytes(read)?).to_string())
+}
+
+fn push_int(reply: &mut Vec<u8>, value: i32) {
+    reply.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_bytes(reply: &mut Vec<u8>, data: &[u8]) {
+    push_int(reply, data.len() as i32);
+    reply.extend_from_slice(data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("upyremote-mount-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("root")).unwrap();
+        dir
+    }
+
+    /// Request arguments as the shim would send them
+    fn request(args: &[&[u8]]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for arg in args {
+            push_bytes(&mut data, arg);
+        }
+        data
+    }
+
+    fn handle(server: &mut MountServer, command: u8, data: Vec<u8>) -> Result<i32> {
+        let mut data = data.into_iter();
+        let reply = server.handle(command, &mut |n| Ok(data.by_ref().take(n).collect()))?;
+        Ok(i32::from_le_bytes([reply[0], reply[1], reply[2], reply[3]]))
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_cannot_leave_the_root() {
+        let dir = scratch("symlink");
+        fs::write(dir.join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink(&dir, dir.join("root/out")).unwrap();
+        std::os::unix::fs::symlink(dir.join("gone"), dir.join("root/dangling")).unwrap();
+        fs::write(dir.join("root/inside.txt"), "inside").unwrap();
+        let mut server = MountServer::new(&dir.join("root"));
+
+        let status = handle(&mut server, b'S', request(&[b"/out/secret.txt"])).unwrap();
+        assert_eq!(status, -EACCES);
+        let status = handle(&mut server, b'O', request(&[b"/out/new.txt", b"w"])).unwrap();
+        assert_eq!(status, -EACCES);
+        let status = handle(&mut server, b'O', request(&[b"/dangling", b"w"])).unwrap();
+        assert_eq!(status, -EACCES);
+        assert!(!dir.join("gone").exists());
+
+        let status = handle(&mut server, b'S', request(&[b"/inside.txt"])).unwrap();
+        assert_eq!(status, 0);
+        let status = handle(&mut server, b'O', request(&[b"/new.txt", b"w"])).unwrap();
+        assert!(status >= 0);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn oversized_lengths_are_not_trusted() {
+        let dir = scratch("lengths");
+        fs::write(dir.join("root/big.bin"), vec![0u8; MAX_DATA * 2]).unwrap();
+        let mut server = MountServer::new(&dir.join("root"));
+
+        let mut huge = Vec::new();
+        push_int(&mut huge, i32::MAX);
+        assert!(handle(&mut server, b'S', huge).is_err());
+
+        let fd = handle(&mut server, b'O', request(&[b"big.bin", b"rb"])).unwrap();
+        let mut data = Vec::new();
+        push_int(&mut data, fd);
+        push_int(&mut data, i32::MAX);
+        assert_eq!(handle(&mut server, b'R', data).unwrap(), MAX_DATA as i32);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,224 @@
+//! Discovery of serial ports with a MicroPython-capable board attached.
+//!
+//! Boards are recognised by the USB vendor/product ID of their native USB
+//! stack or of the USB-serial bridge they are usually built with. A port can
+//! also be selected by USB serial number (`id:<serial>`) or by USB ID
+//! (`vid:pid`, in hex), which stay the same across replugs.
+
+use anyhow::{Context, Result};
+use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
+
+/// Port name that selects the first port with a known board
+pub const AUTO_PORT: &str = "auto";
+
+/// USB IDs of boards and bridges that typically run MicroPython.
+/// A `None` product ID matches every product of the vendor.
+const KNOWN_USB_IDS: &[(u16, Option<u16>, &str)] = &[
+    (0xf055, None, "MicroPython"),
+    (0x2e8a, None, "Raspberry Pi RP2040/RP2350"),
+    (0x303a, None, "Espressif native USB"),
+    (0x10c4, Some(0xea60), "Silicon Labs CP210x"),
+    (0x1a86, Some(0x7523), "WCH CH340"),
+    (0x1a86, Some(0x55d4), "WCH CH9102"),
+    (0x0403, Some(0x6001), "FTDI FT232R"),
+    (0x0403, Some(0x6015), "FTDI FT231X"),
+];
+
+/// Name of the known board or bridge with this USB ID
+pub fn known_board(vid: u16, pid: u16) -> Option<&'static str> {
+    KNOWN_USB_IDS
+        .iter()
+        .find(|(v, p, _)| *v == vid && p.is_none_or(|p| p == pid))
+        .map(|(_, _, name)| *name)
+}
+
+/// Serial ports present on the system, sorted by name
+pub fn available_ports() -> Result<Vec<SerialPortInfo>> {
+    let mut ports = serialport::available_ports().context("Could not enumerate serial ports")?;
+    ports.sort_by(|a, b| a.port_name.cmp(&b.port_name));
+    Ok(ports)
+}
+
+/// USB ID from a `vid:pid` selector such as `2e8a:0005`
+fn parse_usb_id(spec: &str) -> Option<(u16, u16)> {
+    let (vid, pid) = spec.split_once(':')?;
+    let hex = |s: &str| {
+        if s.is_empty() || s.len() > 4 {
+            return None;
+        }
+        u16::from_str_radix(s, 16).ok()
+    };
+    Some((hex(vid)?, hex(pid)?))
+}
+
+/// USB ports matching `filter`, sorted by name
+fn usb_ports(filter: impl Fn(&UsbPortInfo) -> bool) -> Result<Vec<String>> {
+    Ok(matching_ports(available_ports()?, filter))
+}
+
+/// Names of the USB ports in `ports` matching `filter`, in order
+fn matching_ports(
+    ports: Vec<SerialPortInfo>,
+    filter: impl Fn(&UsbPortInfo) -> bool,
+) -> Vec<String> {
+    ports
+        .into_iter()
+        .filter(|port| match &port.port_type {
+            SerialPortType::UsbPort(usb) => filter(usb),
+            _ => false,
+        })
+        .map(|port| port.port_name)
+        .collect()
+}
+
+/// Matches the USB ports of known boards
+fn is_known_board(usb: &UsbPortInfo) -> bool {
+    known_board(usb.vid, usb.pid).is_some()
+}
+
+/// Resolves `auto`, `id:<serial>` and `vid:pid` to a port name.
+/// Any other specification is returned unchanged.
+pub fn resolve(spec: &str) -> Result<String> {
+    if spec == AUTO_PORT {
+        return find_auto_port();
+    }
+
+    if let Some(serial) = spec.strip_prefix("id:") {
+        return usb_ports(|usb| {
+            usb.serial_number
+                .as_deref()
+                .is_some_and(|s| s.eq_ignore_ascii_case(serial))
+        })?
+        .into_iter()
+        .next()
+        .with_context(|| format!("No USB device with serial number '{}' found", serial));
+    }
+
+    if let Some((vid, pid)) = parse_usb_id(spec) {
+        let matches = usb_ports(|usb| usb.vid == vid && usb.pid == pid)?;
+        let Some(first) = matches.first() else {
+            anyhow::bail!("No USB device with ID {:04x}:{:04x} found", vid, pid);
+        };
+        if matches.len() > 1 {
+            println!(
+                "[WARNING] {} devices with ID {:04x}:{:04x} ({}), using {}",
+                matches.len(),
+                vid,
+                pid,
+                matches.join(", "),
+                first
+            );
+        }
+        return Ok(first.clone());
+    }
+
+    Ok(spec.to_string())
+}
+
+/// Name of the first port whose USB ID belongs to a known board
+fn find_auto_port() -> Result<String> {
+    usb_ports(is_known_board)?
+        .into_iter()
+        .next()
+        .context(
+            "No MicroPython board found on any USB port (run 'upyremote devices' to list ports, or use -p)",
+        )
+}
+
+/// Prints one line per port with its USB details
+pub fn print_devices() -> Result<()> {
+    let ports = available_ports()?;
+    if ports.is_empty() {
+        println!("No serial ports found");
+        return Ok(());
+    }
+
+    for port in ports {
+        match port.port_type {
+            SerialPortType::UsbPort(usb) => {
+                let board = known_board(usb.vid, usb.pid);
+                println!(
+                    "{} {:<16} {:04x}:{:04x}  serial={}  {}{}",
+                    if board.is_some() { "*" } else { " " },
+                    port.port_name,
+                    usb.vid,
+                    usb.pid,
+                    usb.serial_number.as_deref().unwrap_or("-"),
+                    usb.product
+                        .or(usb.manufacturer)
+                        .unwrap_or_else(|| "USB serial".to_string()),
+                    board.map(|b| format!(" [{}]", b)).unwrap_or_default(),
+                );
+            }
+            SerialPortType::PciPort => println!("  {:<16} PCI", port.port_name),
+            SerialPortType::BluetoothPort => println!("  {:<16} Bluetooth", port.port_name),
+            SerialPortType::Unknown => println!("  {:<16} -", port.port_name),
+        }
+    }
+    println!("\n* known MicroPython board, selected by '-p auto' (first match)");
+    println!("Select a specific board with -p id:<serial> or -p <vid>:<pid>");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usb_port(name: &str, vid: u16, pid: u16) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: name.to_string(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid,
+                pid,
+                serial_number: None,
+                manufacturer: None,
+                product: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn parses_usb_ids() {
+        assert_eq!(parse_usb_id("2e8a:0005"), Some((0x2e8a, 0x0005)));
+        assert_eq!(parse_usb_id("10C4:EA60"), Some((0x10c4, 0xea60)));
+        assert_eq!(parse_usb_id("f055:9802"), Some((0xf055, 0x9802)));
+        assert_eq!(parse_usb_id("2e8a:"), None);
+        assert_eq!(parse_usb_id("2e8a:00005"), None);
+        assert_eq!(parse_usb_id("2e8a:xyz"), None);
+        assert_eq!(parse_usb_id("/dev/ttyUSB0"), None);
+    }
+
+    #[test]
+    fn recognises_known_boards() {
+        assert_eq!(
+            known_board(0x2e8a, 0x0005),
+            Some("Raspberry Pi RP2040/RP2350")
+        );
+        assert_eq!(known_board(0x10c4, 0xea60), Some("Silicon Labs CP210x"));
+        // Only some products of a bridge vendor are listed
+        assert_eq!(known_board(0x0403, 0x6010), None);
+        assert_eq!(known_board(0x046d, 0xc52b), None);
+    }
+
+    #[test]
+    fn picks_first_known_board() {
+        let ports = vec![
+            SerialPortInfo {
+                port_name: "/dev/ttyS0".to_string(),
+                port_type: SerialPortType::PciPort,
+            },
+            usb_port("/dev/ttyACM0", 0x046d, 0xc52b),
+            usb_port("/dev/ttyUSB0", 0x1a86, 0x7523),
+            usb_port("/dev/ttyUSB1", 0x2e8a, 0x0005),
+        ];
+        assert_eq!(
+            matching_ports(ports.clone(), is_known_board).first(),
+            Some(&"/dev/ttyUSB0".to_string())
+        );
+        assert_eq!(
+            matching_ports(ports, |usb| usb.vid == 0x2e8a && usb.pid == 0x0005),
+            ["/dev/ttyUSB1"]
+        );
+        assert!(matching_ports(vec![], is_known_board).is_empty());
+    }
+}
@@ -0,0 +1,136 @@
+//! Progress line for file transfers: bar, bytes, percent, rate and ETA,
+//! redrawn in place on the terminal.
+
+use std::{
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+/// Minimum time between two redraws
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+const BAR_WIDTH: usize = 20;
+
+pub struct Progress {
+    label: String,
+    /// Size of the transfer, when known in advance
+    total: Option<usize>,
+    done: usize,
+    start: Instant,
+    last_draw: Option<Instant>,
+    enabled: bool,
+}
+
+impl Progress {
+    pub fn new(label: &str, total: Option<usize>, enabled: bool) -> Self {
+        Progress {
+            label: label.to_string(),
+            total,
+            done: 0,
+            start: Instant::now(),
+            last_draw: None,
+            enabled,
+        }
+    }
+
+    pub fn set_total(&mut self, total: usize) {
+        self.total = Some(total);
+    }
+
+    /// Records the number of bytes transferred so far
+    pub fn set(&mut self, done: usize) {
+        self.done = done;
+        if self
+            .last_draw
+            .is_none_or(|last| last.elapsed() >= REDRAW_INTERVAL)
+        {
+            self.draw();
+        }
+    }
+
+    pub fn add(&mut self, bytes: usize) {
+        self.set(self.done + bytes);
+    }
+
+    /// Draws the final state and moves to the next line
+    pub fn finish(&mut self) {
+        if self.enabled {
+            self.draw();
+            println!();
+            self.enabled = false;
+        }
+    }
+
+    fn draw(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        self.last_draw = Some(Instant::now());
+
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            self.done as f64 / elapsed
+        } else {
+            0.0
+        };
+
+        let mut line = format!("\r{} ", self.label);
+        match self.total {
+            Some(total) => {
+                let fraction = if total == 0 {
+                    1.0
+                } else {
+                    (self.done as f64 / total as f64).min(1.0)
+                };
+                let filled = (fraction * BAR_WIDTH as f64) as usize;
+                line.push_str(&format!(
+                    "[{}{}] {:3.0}% {}/{} {}/s",
+                    "#".repeat(filled),
+                    "-".repeat(BAR_WIDTH - filled),
+                    fraction * 100.0,
+                    human_bytes(self.done as f64),
+                    human_bytes(total as f64),
+                    human_bytes(rate)
+                ));
+                if self.done < total && rate > 0.0 {
+                    let eta = ((total - self.done) as f64 / rate) as u64;
+                    line.push_str(&format!(" ETA {}:{:02}", eta / 60, eta % 60));
+                } else {
+                    line.push_str(&format!(" in {:.1} s", elapsed));
+                }
+            }
+            None => line.push_str(&format!(
+                "{} {}/s",
+                human_bytes(self.done as f64),
+                human_bytes(rate)
+            )),
+        }
+        // Clear what is left of a longer previous line
+        line.push_str("\x1b[K");
+
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(line.as_bytes());
+        let _ = stdout.flush();
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        // An interrupted transfer leaves its line in place for the error below it
+        if self.enabled && self.last_draw.is_some() {
+            println!();
+        }
+    }
+}
+
+/// Byte count with a B, KB, MB or GB unit
+pub fn human_bytes(bytes: f64) -> String {
+    if bytes < 1024.0 {
+        format!("{:.0} B", bytes)
+    } else if bytes < 1024.0 * 1024.0 {
+        format!("{:.1} KB", bytes / 1024.0)
+    } else if bytes < 1024.0 * 1024.0 * 1024.0 {
+        format!("{:.1} MB", bytes / (1024.0 * 1024.0))
+    } else {
+        format!("{:.1} GB", bytes / (1024.0 * 1024.0 * 1024.0))
+    }
+}
@@ -0,0 +1,40 @@
+//! Quoting for values placed in generated Python code and in upyOS shell
+//! commands, so that a file name is always passed as data and never
+//! becomes part of the code or command around it.
+
+/// `s` as a Python string literal. Quotes and backslashes are escaped, and
+/// control characters too, as the raw REPL and `fileup` would act on them.
+pub fn py_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('\'');
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\'' => out.push_str("\\'"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 || c == '\x7f' => out.push_str(&format!("\\x{:02x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('\'');
+    out
+}
+
+/// Python list literal of strings
+pub fn py_list(items: &[String]) -> String {
+    let items: Vec<String> = items.iter().map(|item| py_str(item)).collect();
+    format!("[{}]", items.join(", "))
+}
+
+/// `s` as one argument of a upyOS shell command. The shell splits its
+/// command line at spaces and has no quoting, so arguments it would split
+/// or treat specially are refused rather than passed on.
+pub fn shell_arg(s: &str) -> anyhow::Result<&str> {
+    let special = |c: char| c.is_whitespace() || c.is_control() || "\"'\\<>|&;$`".contains(c);
+    if s.is_empty() || s.chars().any(special) {
+        anyhow::bail!("'{}' can't be passed to a upyOS shell command", s);
+    }
+    Ok(s)
+}
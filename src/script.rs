@@ -0,0 +1,169 @@
+//! Script files for `upyremote script`: one command per line, as it would
+//! be given on the command line without `upyremote`.
+//!
+//! Words are split like in a shell: quotes group words, `\` escapes the
+//! next character outside single quotes, `${NAME}` is replaced by a
+//! variable outside single quotes and `#` starts a comment. Two directives
+//! are handled here: `set NAME VALUE` defines a variable and
+//! `on-error continue|abort` decides what a failing command does to the
+//! commands after it.
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::collections::BTreeMap;
+
+use crate::{ChainedCommand, Commands};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OnError {
+    Abort,
+    Continue,
+}
+
+/// A command of a script
+pub struct Step {
+    /// Line number, from 1
+    pub line: usize,
+    pub text: String,
+    pub command: Commands,
+    pub on_error: OnError,
+}
+
+/// Script variables. Values given on the command line win over `set`
+/// lines, which win over environment variables.
+pub struct Vars {
+    fixed: BTreeMap<String, String>,
+    set: BTreeMap<String, String>,
+}
+
+impl Vars {
+    /// Variables from `NAME=VALUE` assignments
+    pub fn new(assignments: &[String]) -> Result<Self> {
+        let mut fixed = BTreeMap::new();
+        for assignment in assignments {
+            let (name, value) = assignment
+                .split_once('=')
+                .with_context(|| format!("Expected NAME=VALUE, got '{}'", assignment))?;
+            fixed.insert(name.to_string(), value.to_string());
+        }
+        Ok(Vars {
+            fixed,
+            set: BTreeMap::new(),
+        })
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        self.fixed
+            .get(name)
+            .or_else(|| self.set.get(name))
+            .cloned()
+            .or_else(|| std::env::var(name).ok())
+    }
+}
+
+/// Parses a whole script, so that no command runs when a line is wrong.
+/// Errors name the line as `<name>:<line>`.
+pub fn parse(text: &str, name: &str, vars: &mut Vars) -> Result<Vec<Step>> {
+    let mut steps = Vec::new();
+    let mut on_error = OnError::Abort;
+    for (i, line) in text.lines().enumerate() {
+        let line_no = i + 1;
+        let at = || format!("{}:{}", name, line_no);
+        let words = split_words(line, vars).with_context(at)?;
+        match words.first().map(String::as_str) {
+            None => {}
+            Some("set") => {
+                let [_, name, value] = &words[..] else {
+                    anyhow::bail!("{}: expected 'set NAME VALUE'", at());
+                };
+                vars.set.insert(name.clone(), value.clone());
+            }
+            Some("on-error") => {
+                on_error = match words.get(1).map(String::as_str) {
+                    Some("continue") if words.len() == 2 => OnError::Continue,
+                    Some("abort") if words.len() == 2 => OnError::Abort,
+                    _ => {
+                        anyhow::bail!("{}: expected 'on-error continue' or 'on-error abort'", at())
+                    }
+                };
+            }
+            Some("script") => anyhow::bail!("{}: scripts can't run other scripts", at()),
+            Some(_) => {
+                let command = ChainedCommand::try_parse_from(&words)
+                    .map_err(|e| anyhow::anyhow!("{}: {}", at(), e.to_string().trim_end()))?
+                    .command;
+                steps.push(Step {
+                    line: line_no,
+                    text: line.trim().to_string(),
+                    command,
+                    on_error,
+                });
+            }
+        }
+    }
+    Ok(steps)
+}
+
+/// Splits a line into words, replacing variables and dropping a comment
+fn split_words(line: &str, vars: &Vars) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    // A word was started, possibly an empty quoted one
+    let mut in_word = false;
+    let mut quote: Option<char> = None;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), c) => word.push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (_, '\\') => {
+                let escaped = chars.next().context("Line ends with '\\'")?;
+                // Inside double quotes only quotes, `\` and `$` need escaping
+                if quote.is_some() && !matches!(escaped, '"' | '\\' | '$') {
+                    word.push('\\');
+                }
+                word.push(escaped);
+                in_word = true;
+            }
+            (_, '$') if chars.peek() == Some(&'{') => {
+                chars.next();
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => anyhow::bail!("Unterminated '${{'"),
+                    }
+                }
+                let value = vars
+                    .get(&name)
+                    .with_context(|| format!("Variable '{}' is not set", name))?;
+                word.push_str(&value);
+                in_word = true;
+            }
+            (None, '#') if !in_word => break,
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (_, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quote.is_some() {
+        anyhow::bail!("Unterminated quote");
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
@@ -0,0 +1,397 @@
+# Device-side half of the upyremote simulator.
+#
+# Runs the Python code that the simulated REPL receives, in an environment
+# that looks enough like MicroPython for upyremote's generated code: the
+# filesystem is rooted in a host directory and the u-modules are aliased.
+#
+# Talks to upyremote with framed messages (1 byte kind, 4 byte big-endian
+# length, payload). stdin carries X (exec), S (single/REPL line), R (reset),
+# I (input for the running code) and C (interrupt). stdout carries
+# O (output) and E (end of execution, payload is the traceback or empty).
+
+import binascii
+import builtins
+import errno
+import gc
+import hashlib
+import importlib.util
+import os
+import queue
+import struct
+import sys
+import threading
+import time
+import types
+import _thread
+
+ROOT = os.path.realpath(sys.argv[1])
+os.chdir(ROOT)
+
+_out = os.fdopen(1, "wb", buffering=0)
+_in = os.fdopen(0, "rb", buffering=0)
+_out_lock = threading.Lock()
+
+
+def send(kind, data=b""):
+    with _out_lock:
+        _out.write(kind + struct.pack(">I", len(data)) + data)
+
+
+def read_exact(n):
+    data = b""
+    while len(data) < n:
+        chunk = _in.read(n - len(data))
+        if not chunk:
+            os._exit(0)
+        data += chunk
+    return data
+
+
+# --- stdout/stdin of the running code --------------------------------------
+
+
+class _OutBuffer:
+    def write(self, data):
+        send(b"O", bytes(data))
+        return len(data)
+
+    def flush(self):
+        pass
+
+
+class _Stdout:
+    buffer = _OutBuffer()
+
+    def write(self, text):
+        self.buffer.write(text.encode())
+        return len(text)
+
+    def flush(self):
+        pass
+
+
+_input = bytearray()
+_input_cv = threading.Condition()
+
+
+class _InBuffer:
+    def read(self, n=1):
+        with _input_cv:
+            while len(_input) < n:
+                _input_cv.wait(0.05)
+            data = bytes(_input[:n])
+            del _input[:n]
+        return data
+
+    def readinto(self, buf):
+        data = self.read(len(buf))
+        buf[: len(data)] = data
+        return len(data)
+
+
+class _Stdin:
+    buffer = _InBuffer()
+
+    def read(self, n=1):
+        return self.buffer.read(n).decode()
+
+    def readline(self):
+        line = b""
+        while not line.endswith(b"\n"):
+            line += self.buffer.read(1)
+        return line.decode()
+
+
+sys.stdout = _Stdout()
+sys.stderr = sys.stdout
+sys.stdin = _Stdin()
+
+# --- filesystem rooted at ROOT ----------------------------------------------
+
+_real_open = builtins.open
+_os = types.SimpleNamespace(**{name: getattr(os, name) for name in dir(os)})
+
+
+def _host(path):
+    path = os.fspath(path)
+    if isinstance(path, bytes):
+        path = path.decode()
+    if path.startswith("/"):
+        return os.path.join(ROOT, os.path.normpath(path).lstrip("/"))
+    return path
+
+
+# Filesystems mounted with os.mount, by mount point
+_mounts = {}
+# Mount point holding the current directory, if it is on a mounted filesystem
+_cwd_mount = None
+
+
+def _mounted(path):
+    """(mount point, filesystem, path on it) for a path on a mounted filesystem"""
+    if not _mounts or isinstance(path, int):
+        return None
+    path = os.fspath(path)
+    if isinstance(path, bytes):
+        path = path.decode()
+    if not path.startswith("/"):
+        # The filesystem resolves relative paths against its own directory
+        if _cwd_mount is None:
+            return None
+        return _cwd_mount, _mounts[_cwd_mount], path
+    path = os.path.normpath(path)
+    for point, vfs in _mounts.items():
+        if path == point or path.startswith(point + "/"):
+            return point, vfs, path[len(point) :] or "/"
+    return None
+
+
+def _routed(name, host_fn):
+    """os function that calls the mounted filesystem's method for its paths"""
+
+    def call(path=".", *args):
+        mounted = _mounted(path)
+        if mounted:
+            return getattr(mounted[1], name)(mounted[2], *args)
+        return host_fn(path, *args)
+
+    return call
+
+
+def _open(file, mode="r", *args, **kwargs):
+    if isinstance(file, int):
+        return _real_open(file, mode, *args, **kwargs)
+    mounted = _mounted(file)
+    if mounted:
+        return mounted[1].open(mounted[2], mode)
+    return _real_open(_host(file), mode, *args, **kwargs)
+
+
+def _getcwd():
+    if _cwd_mount is not None:
+        cwd = _mounts[_cwd_mount].getcwd()
+        return _cwd_mount if cwd == "/" else _cwd_mount + cwd
+    rel = os.path.relpath(_os.getcwd(), ROOT)
+    return "/" if rel == "." else "/" + rel
+
+
+def _chdir(path):
+    global _cwd_mount
+    mounted = _mounted(path)
+    if mounted:
+        mounted[1].chdir(mounted[2])
+        _cwd_mount = mounted[0]
+    else:
+        _os.chdir(_host(path))
+        _cwd_mount = None
+
+
+def _listdir(path="."):
+    mounted = _mounted(path)
+    if mounted:
+        return [entry[0] for entry in mounted[1].ilistdir(mounted[2])]
+    return sorted(_os.listdir(_host(path)))
+
+
+def _rename(old, new):
+    mounted = _mounted(old)
+    if not mounted:
+        return _os.rename(_host(old), _host(new))
+    target = _mounted(new)
+    if not target or target[1] is not mounted[1]:
+        raise OSError(errno.EXDEV, "")
+    mounted[1].rename(mounted[2], target[2])
+
+
+def _ilistdir(path="."):
+    for entry in _os.scandir(_host(path)):
+        kind = 0x4000 if entry.is_dir() else 0x8000
+        size = 0 if entry.is_dir() else entry.stat().st_size
+        yield (entry.name, kind, 0, size)
+
+
+def _mount(vfs, point, readonly=False):
+    point = "/" + point.strip("/")
+    if point in _mounts:
+        raise OSError(errno.EPERM, "")
+    vfs.mount(readonly, False)
+    _mounts[point] = vfs
+
+
+def _umount(point):
+    global _cwd_mount
+    point = "/" + point.strip("/")
+    if point not in _mounts:
+        raise OSError(errno.EINVAL, "")
+    _mounts.pop(point).umount()
+    if _cwd_mount == point:
+        _cwd_mount = None
+        _os.chdir(ROOT)
+
+
+class _MountFinder:
+    """Imports modules from mounted filesystems on sys.path, as MicroPython does"""
+
+    @staticmethod
+    def find_spec(name, path=None, target=None):
+        if not _mounts:
+            return None
+        for entry in sys.path:
+            base = _getcwd() if entry in ("", ".") else entry
+            file = base.rstrip("/") + "/" + name.replace(".", "/") + ".py"
+            if _mounted(file):
+                try:
+                    os.stat(file)
+                except OSError:
+                    continue
+                return importlib.util.spec_from_loader(name, _MountLoader(file))
+        return None
+
+
+class _MountLoader:
+    def __init__(self, path):
+        self.path = path
+
+    def create_module(self, spec):
+        return None
+
+    def exec_module(self, module):
+        with _open(self.path) as f:
+            source = f.read()
+        exec(compile(source, self.path, "exec"), module.__dict__)
+
+
+builtins.open = _open
+os.listdir = _listdir
+os.ilistdir = _routed("ilistdir", _ilistdir)
+os.stat = _routed("stat", lambda path: _os.stat(_host(path)))
+os.statvfs = _routed("statvfs", lambda path: _os.statvfs(_host(path)))
+os.remove = _routed("remove", lambda path: _os.remove(_host(path)))
+os.unlink = os.remove
+os.mkdir = _routed("mkdir", lambda path, *args: _os.mkdir(_host(path)))
+os.rmdir = _routed("rmdir", lambda path: _os.rmdir(_host(path)))
+os.rename = _rename
+os.chdir = _chdir
+os.getcwd = _getcwd
+os.mount = _mount
+os.umount = _umount
+sys.meta_path.insert(0, _MountFinder)
+
+# --- MicroPython module names -----------------------------------------------
+
+time.ticks_ms = lambda: int(time.monotonic() * 1000)
+time.ticks_diff = lambda a, b: a - b
+time.sleep_ms = lambda ms: time.sleep(ms / 1000)
+gc.mem_free = lambda: 100000
+gc.mem_alloc = lambda: 20000
+
+_micropython = types.ModuleType("micropython")
+_micropython.const = lambda value: value
+# Tells the simulator which input byte interrupts running code (-1: none)
+_micropython.kbd_intr = lambda char: send(b"K", bytes([char]) if char >= 0 else b"")
+_micropython.mem_info = lambda *args: None
+
+sys.modules.update(
+    {
+        "ubinascii": binascii,
+        "uhashlib": hashlib,
+        "uos": os,
+        "utime": time,
+        "usys": sys,
+        "micropython": _micropython,
+    }
+)
+
+# --- execution ----------------------------------------------------------------
+
+_jobs = queue.Queue()
+_busy = False
+
+
+def _reader():
+    while True:
+        header = read_exact(5)
+        kind, length = header[:1], struct.unpack(">I", header[1:])[0]
+        data = read_exact(length)
+        if kind == b"I":
+            with _input_cv:
+                _input.extend(data)
+                _input_cv.notify_all()
+        elif kind == b"C":
+            if _busy:
+                _thread.interrupt_main()
+        else:
+            _jobs.put((kind, data))
+
+
+def _format_exception(exc):
+    lines = ["Traceback (most recent call last):"]
+    if isinstance(exc, SyntaxError):
+        lines.append('  File "<stdin>", line %d' % (exc.lineno or 1))
+    else:
+        tb = exc.__traceback__
+        while tb is not None:
+            code = tb.tb_frame.f_code
+            if code.co_filename == "<stdin>":
+                lines.append(
+                    '  File "<stdin>", line %d, in %s' % (tb.tb_lineno, code.co_name)
+                )
+            tb = tb.tb_next
+    message = exc.msg if isinstance(exc, SyntaxError) else str(exc)
+    name = type(exc).__name__
+    code = exc.errno if isinstance(exc, OSError) else None
+    if isinstance(exc, OSError) and code is None and len(exc.args) == 1:
+        # OSError(errno) as raised by MicroPython code
+        code = exc.args[0] if isinstance(exc.args[0], int) else None
+    if code is not None:
+        # MicroPython reports a plain OSError with the errno name
+        name = "OSError"
+        message = "[Errno %d] %s" % (code, errno.errorcode.get(code, "EIO"))
+    lines.append("%s: %s" % (name, message) if message else name)
+    return "\r\n".join(lines) + "\r\n"
+
+
+def _namespace():
+    return {"__name__": "__main__", "__builtins__": builtins}
+
+
+def main():
+    global _busy, _cwd_mount
+    threading.Thread(target=_reader, daemon=True).start()
+    namespace = _namespace()
+    # Modules imported by device code are forgotten on reset, as on MicroPython
+    system_modules = set(sys.modules)
+
+    while True:
+        try:
+            kind, data = _jobs.get()
+        except KeyboardInterrupt:
+            continue
+
+        if kind == b"R":
+            namespace = _namespace()
+            for name in set(sys.modules) - system_modules:
+                del sys.modules[name]
+            _mounts.clear()
+            _cwd_mount = None
+            _os.chdir(ROOT)
+            continue
+
+        traceback = ""
+        _busy = True
+        try:
+            mode = "exec" if kind == b"X" else "single"
+            exec(compile(data.decode(), "<stdin>", mode), namespace)
+        except BaseException as exc:
+            traceback = _format_exception(exc)
+        finally:
+            _busy = False
+        with _input_cv:
+            _input.clear()
+        send(b"E", traceback.encode())
+
+
+try:
+    main()
+except KeyboardInterrupt:
+    pass
@@ -0,0 +1,829 @@
+//! Simulated device for offline testing.
+//!
+//! `SimDevice` behaves like a board on the other end of a serial cable: it
+//! emulates the friendly REPL, the raw REPL (including raw-paste mode) and a
+//! minimal upyOS shell. Its filesystem is a host directory. Python code is
+//! executed by a `python3` worker process (see `sim_worker.py`) so that the
+//! code upyremote generates runs for real.
+//!
+//! Select it with `-p sim:<mode>[:<root>]`, or serve it over TCP with
+//! `upyremote simulate` and connect with `-p tcp://host:port`.
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::Duration,
+};
+
+use crate::transport::{READ_TIMEOUT, Transport};
+
+const WORKER_SCRIPT: &str = include_str!("sim_worker.py");
+const BANNER: &str = "MicroPython v1.24.0 on upyremote-sim; simulated device\r\n\
+                      Type \"help()\" for more information.\r\n";
+const RAW_BANNER: &str = "raw REPL; CTRL-B to exit\r\n>";
+/// Flash size reported by the upyOS `df` command
+const SIM_FLASH_SIZE: u64 = 2 * 1024 * 1024;
+/// Window size announced in raw-paste mode; kept small so flow control is exercised
+const RAW_PASTE_WINDOW: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum SimMode {
+    /// MicroPython REPL with raw-paste support
+    Repl,
+    /// MicroPython REPL on firmware without raw-paste support
+    ReplLegacy,
+    /// upyOS shell
+    Upyos,
+}
+
+impl SimMode {
+    fn description(&self) -> &'static str {
+        match self {
+            SimMode::Repl => "MicroPython REPL",
+            SimMode::ReplLegacy => "MicroPython REPL (no raw-paste)",
+            SimMode::Upyos => "upyOS",
+        }
+    }
+}
+
+/// Where the simulated device is in its input handling
+enum State {
+    Friendly {
+        line: Vec<u8>,
+    },
+    Raw {
+        code: Vec<u8>,
+    },
+    RawPaste {
+        code: Vec<u8>,
+        received: usize,
+    },
+    /// Code is running in the worker
+    Running {
+        resume: Resume,
+    },
+    Shell {
+        line: Vec<u8>,
+    },
+    Fileup {
+        path: String,
+        line: Vec<u8>,
+        content: Vec<u8>,
+    },
+}
+
+/// Where input handling continues once running code finishes
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Resume {
+    Friendly,
+    Raw,
+    /// upyOS shell, after `python <file>`
+    Shell,
+}
+
+enum WorkerEvent {
+    Output(Vec<u8>),
+    /// Execution finished; carries the traceback, empty on success
+    Done(Vec<u8>),
+    /// Running code changed its interrupt character with `micropython.kbd_intr`
+    InterruptChar(Option<u8>),
+}
+
+struct Worker {
+    child: Child,
+    stdin: ChildStdin,
+    events: Receiver<WorkerEvent>,
+}
+
+impl Worker {
+    fn spawn(root: &Path) -> Result<Self> {
+        let mut child = Command::new("python3")
+            .arg("-u")
+            .arg("-c")
+            .arg(WORKER_SCRIPT)
+            .arg(root)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .context("The simulator needs python3 to run device code")?;
+
+        let stdin = child
+            .stdin
+            .take()
+            .context("No stdin for simulator worker")?;
+        let mut stdout = child
+            .stdout
+            .take()
+            .context("No stdout for simulator worker")?;
+
+        let (tx, events) = mpsc::channel();
+        thread::spawn(move || {
+            loop {
+                let mut header = [0u8; 5];
+                if stdout.read_exact(&mut header).is_err() {
+                    let _ = tx.send(WorkerEvent::Done(
+                        b"SystemExit: worker stopped\r\n".to_vec(),
+                    ));
+                    return;
+                }
+                let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+                let mut data = vec![0u8; len as usize];
+                if stdout.read_exact(&mut data).is_err() {
+                    return;
+                }
+                let event = match header[0] {
+                    b'O' => WorkerEvent::Output(data),
+                    b'K' => WorkerEvent::InterruptChar(data.first().copied()),
+                    _ => WorkerEvent::Done(data),
+                };
+                if tx.send(event).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(Worker {
+            child,
+            stdin,
+            events,
+        })
+    }
+
+    fn send(&mut self, kind: u8, data: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(data.len() + 5);
+        frame.push(kind);
+        frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        frame.extend_from_slice(data);
+        self.stdin.write_all(&frame)?;
+        self.stdin.flush()
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub struct SimDevice {
+    mode: SimMode,
+    root: PathBuf,
+    state: State,
+    output: VecDeque<u8>,
+    worker: Option<Worker>,
+    /// Input for running code, sent to the worker once a write is processed
+    input: Vec<u8>,
+    /// Current directory of the upyOS shell
+    cwd: String,
+    /// Input byte that interrupts running code, `None` when disabled
+    interrupt_char: Option<u8>,
+}
+
+impl SimDevice {
+    pub fn new(mode: SimMode, root: &Path) -> Result<Self> {
+        std::fs::create_dir_all(root)
+            .with_context(|| format!("Could not create simulator root {}", root.display()))?;
+        let root = root.canonicalize()?;
+
+        let state = match mode {
+            SimMode::Repl | SimMode::ReplLegacy => State::Friendly { line: vec![] },
+            SimMode::Upyos => State::Shell { line: vec![] },
+        };
+
+        Ok(SimDevice {
+            mode,
+            root,
+            state,
+            output: VecDeque::new(),
+            worker: None,
+            input: Vec::new(),
+            cwd: "/".to_string(),
+            interrupt_char: Some(0x03),
+        })
+    }
+
+    /// Opens a device from a `sim:<mode>[:<root>]` port name. Without a root
+    /// the device keeps its files in a shared directory under the system temp dir.
+    pub fn open(port_name: &str) -> Result<Self> {
+        let spec = port_name
+            .strip_prefix("sim:")
+            .with_context(|| format!("Invalid simulator port '{}'", port_name))?;
+        let (mode, root) = match spec.split_once(':') {
+            Some((mode, root)) => (mode, PathBuf::from(root)),
+            None => (spec, std::env::temp_dir().join("upyremote-sim")),
+        };
+        let mode = SimMode::from_str(mode, true).map_err(|_| {
+            anyhow::anyhow!(
+                "Unknown simulator mode '{}' (expected repl, repl-legacy or upyos)",
+                mode
+            )
+        })?;
+        Self::new(mode, &root)
+    }
+
+    fn emit(&mut self, data: &[u8]) {
+        self.output.extend(data);
+    }
+
+    fn prompt(&mut self) {
+        let prompt = format!("{} $: ", self.cwd);
+        self.emit(prompt.as_bytes());
+    }
+
+    fn worker(&mut self) -> io::Result<&mut Worker> {
+        if self.worker.is_none() {
+            let worker = Worker::spawn(&self.root).map_err(io::Error::other)?;
+            self.worker = Some(worker);
+        }
+        Ok(self.worker.as_mut().expect("worker was just spawned"))
+    }
+
+    /// Starts code in the worker; the caller switches to `State::Running`.
+    /// REPL lines are compiled in single mode so expression values are echoed.
+    fn run_code(&mut self, code: &[u8], resume: Resume) -> io::Result<()> {
+        let kind = if resume == Resume::Friendly {
+            b'S'
+        } else {
+            b'X'
+        };
+        // Each execution starts with Ctrl-C enabled, as on MicroPython
+        self.interrupt_char = Some(0x03);
+        self.worker()?.send(kind, code)
+    }
+
+    fn soft_reset(&mut self) -> io::Result<()> {
+        if let Some(worker) = self.worker.as_mut() {
+            worker.send(b'R', &[])?;
+        }
+        self.emit(b"MPY: soft reboot\r\n");
+        Ok(())
+    }
+
+    /// Moves worker output into the output queue, waiting up to `timeout` for some
+    fn pump(&mut self, timeout: Option<Duration>) {
+        let Some(worker) = self.worker.as_ref() else {
+            return;
+        };
+        let mut events = Vec::new();
+        if let Some(timeout) = timeout
+            && let Ok(event) = worker.events.recv_timeout(timeout)
+        {
+            events.push(event);
+        }
+        events.extend(worker.events.try_iter());
+
+        for event in events {
+            match event {
+                WorkerEvent::Output(data) => self.emit(&data),
+                WorkerEvent::InterruptChar(byte) => self.interrupt_char = byte,
+                WorkerEvent::Done(traceback) => match self.state {
+                    State::Running {
+                        resume: Resume::Raw,
+                    } => {
+                        self.emit(b"\x04");
+                        self.emit(&traceback);
+                        self.emit(b"\x04>");
+                        self.state = State::Raw { code: vec![] };
+                    }
+                    State::Running {
+                        resume: Resume::Shell,
+                    } => {
+                        self.emit(&traceback);
+                        self.prompt();
+                        self.state = State::Shell { line: vec![] };
+                    }
+                    _ => {
+                        self.emit(&traceback);
+                        self.emit(b">>> ");
+                        self.state = State::Friendly { line: vec![] };
+                    }
+                },
+            }
+        }
+    }
+
+    fn feed(&mut self, byte: u8) -> io::Result<()> {
+        let state = std::mem::replace(
+            &mut self.state,
+            State::Running {
+                resume: Resume::Friendly,
+            },
+        );
+        self.state = match state {
+            State::Friendly { line } => self.feed_friendly(line, byte)?,
+            State::Raw { code } => self.feed_raw(code, byte)?,
+            State::RawPaste { code, received } => self.feed_raw_paste(code, received, byte)?,
+            State::Running { resume } => {
+                if Some(byte) == self.interrupt_char {
+                    self.worker()?.send(b'C', &[])?;
+                } else {
+                    self.input.push(byte);
+                }
+                State::Running { resume }
+            }
+            State::Shell { line } => self.feed_shell(line, byte)?,
+            State::Fileup {
+                path,
+                line,
+                content,
+            } => self.feed_fileup(path, line, content, byte)?,
+        };
+        Ok(())
+    }
+
+    fn feed_friendly(&mut self, mut line: Vec<u8>, byte: u8) -> io::Result<State> {
+        match byte {
+            0x01 => {
+                self.emit(b"\r\n");
+                self.emit(RAW_BANNER.as_bytes());
+                return Ok(State::Raw { code: vec![] });
+            }
+            0x02 => {
+                self.emit(b"\r\n");
+                self.emit(BANNER.as_bytes());
+                self.emit(b">>> ");
+                line.clear();
+            }
+            0x03 => {
+                self.emit(b"\r\n>>> ");
+                line.clear();
+            }
+            0x04 if line.is_empty() => {
+                self.emit(b"\r\n");
+                self.soft_reset()?;
+                self.emit(BANNER.as_bytes());
+                self.emit(b">>> ");
+            }
+            b'\r' => {
+                self.emit(b"\r\n");
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    self.emit(b">>> ");
+                    line.clear();
+                } else {
+                    self.run_code(&line, Resume::Friendly)?;
+                    return Ok(State::Running {
+                        resume: Resume::Friendly,
+                    });
+                }
+            }
+            0x08 | 0x7f if line.pop().is_some() => self.emit(b"\x08 \x08"),
+            0x20..=0x7e => {
+                line.push(byte);
+                self.emit(&[byte]);
+            }
+            _ => {}
+        }
+        Ok(State::Friendly { line })
+    }
+
+    fn feed_raw(&mut self, mut code: Vec<u8>, byte: u8) -> io::Result<State> {
+        match byte {
+            // Ctrl-E A Ctrl-A requests raw-paste mode
+            0x01 if code == b"\x05A" => {
+                if self.mode == SimMode::ReplLegacy {
+                    self.emit(b"R\x00");
+                    code.clear();
+                } else {
+                    self.emit(b"R\x01");
+                    self.emit(&(RAW_PASTE_WINDOW as u16).to_le_bytes());
+                    return Ok(State::RawPaste {
+                        code: vec![],
+                        received: 0,
+                    });
+                }
+            }
+            0x01 => {
+                self.emit(b"\r\n");
+                self.emit(RAW_BANNER.as_bytes());
+                code.clear();
+            }
+            0x02 => {
+                self.emit(b"\r\n");
+                self.emit(BANNER.as_bytes());
+                self.emit(b">>> ");
+                return Ok(State::Friendly { line: vec![] });
+            }
+            0x03 => {}
+            0x04 if code.is_empty() => {
+                self.emit(b"OK\r\n");
+                self.soft_reset()?;
+                self.emit(RAW_BANNER.as_bytes());
+            }
+            0x04 => {
+                self.emit(b"OK");
+                self.run_code(&code, Resume::Raw)?;
+                return Ok(State::Running {
+                    resume: Resume::Raw,
+                });
+            }
+            _ => code.push(byte),
+        }
+        Ok(State::Raw { code })
+    }
+
+    fn feed_raw_paste(
+        &mut self,
+        mut code: Vec<u8>,
+        received: usize,
+        byte: u8,
+    ) -> io::Result<State> {
+        if byte == 0x04 {
+            self.emit(b"\x04");
+            self.run_code(&code, Resume::Raw)?;
+            return Ok(State::Running {
+                resume: Resume::Raw,
+            });
+        }
+
+        code.push(byte);
+        let received = received + 1;
+        if received.is_multiple_of(RAW_PASTE_WINDOW) {
+            // Room for another window
+            self.emit(b"\x01");
+        }
+        Ok(State::RawPaste { code, received })
+    }
+
+    fn feed_shell(&mut self, mut line: Vec<u8>, byte: u8) -> io::Result<State> {
+        match byte {
+            b'\r' => {
+                self.emit(b"\r\n");
+                let command = String::from_utf8_lossy(&line).to_string();
+                if let Some(state) = self.run_shell_command(command.trim()) {
+                    return Ok(state);
+                }
+                self.prompt();
+                line.clear();
+            }
+            0x03 => {
+                self.emit(b"^C\r\n");
+                self.prompt();
+                line.clear();
+            }
+            0x08 | 0x7f if line.pop().is_some() => self.emit(b"\x08 \x08"),
+            0x20..=0x7e => {
+                line.push(byte);
+                self.emit(&[byte]);
+            }
+            _ => {}
+        }
+        Ok(State::Shell { line })
+    }
+
+    fn feed_fileup(
+        &mut self,
+        path: String,
+        mut line: Vec<u8>,
+        mut content: Vec<u8>,
+        byte: u8,
+    ) -> io::Result<State> {
+        match byte {
+            0x04 => {
+                content.extend_from_slice(&line);
+                let result = std::fs::write(self.host_path(&path), &content);
+                match result {
+                    Ok(()) => self.emit(b"\r\nFile saved\r\n"),
+                    Err(e) => self.emit(format!("\r\nfileup: {}\r\n", e).as_bytes()),
+                }
+                self.prompt();
+                return Ok(State::Shell { line: vec![] });
+            }
+            b'\r' => {
+                content.extend_from_slice(&line);
+                content.push(b'\n');
+                line.clear();
+                self.emit(b"\r\n>");
+            }
+            b'\n' => {}
+            _ => {
+                line.push(byte);
+                self.emit(&[byte]);
+            }
+        }
+        Ok(State::Fileup {
+            path,
+            line,
+            content,
+        })
+    }
+
+    /// Maps a device path, absolute or relative to the shell's directory, into the root
+    fn host_path(&self, path: &str) -> PathBuf {
+        let absolute = if path.starts_with('/') {
+            path.to_string()
+        } else {
+            format!("{}/{}", self.cwd.trim_end_matches('/'), path)
+        };
+
+        let mut host = self.root.clone();
+        for part in absolute.split('/') {
+            match part {
+                "" | "." => {}
+                ".." => {
+                    if host != self.root {
+                        host.pop();
+                    }
+                }
+                part => host.push(part),
+            }
+        }
+        host
+    }
+
+    fn shell_print(&mut self, text: &str) {
+        for line in text.lines() {
+            self.emit(line.as_bytes());
+            self.emit(b"\r\n");
+        }
+    }
+
+    /// Runs a upyOS shell command. Returns a new state for commands that
+    /// take over the input (`fileup`, `python`), `None` when the prompt should follow.
+    fn run_shell_command(&mut self, command: &str) -> Option<State> {
+        let args: Vec<&str> = command.split_whitespace().collect();
+        let (&name, args) = args.split_first()?;
+
+        match name {
+            "echo" => {
+                let text = args
+                    .iter()
+                    .map(|a| if *a == "$SHELL" { "/bin/sh" } else { a })
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                self.shell_print(&text);
+            }
+            "df" => {
+                let used = dir_size(&self.root).div_ceil(1024);
+                let total = (SIM_FLASH_SIZE / 1024).max(used);
+                self.shell_print(&format!(
+                    "Filesystem  1K-blocks    Used  Available  Use%  Mounted on\n\
+                     flash       {:>9} {:>7} {:>10} {:>4}%  /",
+                    total,
+                    used,
+                    total - used,
+                    (used * 100).div_ceil(total)
+                ));
+            }
+            "pwd" => {
+                let cwd = self.cwd.clone();
+                self.shell_print(&cwd);
+            }
+            "cd" => {
+                let target = args.first().copied().unwrap_or("/");
+                if self.host_path(target).is_dir() {
+                    let host = self.host_path(target);
+                    let rel = host.strip_prefix(&self.root).unwrap_or(Path::new(""));
+                    self.cwd = format!("/{}", rel.to_string_lossy());
+                } else {
+                    self.shell_print(&format!("cd: {}: No such directory", target));
+                }
+            }
+            "ls" => {
+                let one_per_line = args.contains(&"-1");
+                let path = args
+                    .iter()
+                    .find(|a| !a.starts_with('-'))
+                    .copied()
+                    .unwrap_or(".");
+                match std::fs::read_dir(self.host_path(path)) {
+                    Ok(entries) => {
+                        let mut names: Vec<String> = entries
+                            .filter_map(|e| e.ok())
+                            .map(|e| e.file_name().to_string_lossy().to_string())
+                            .collect();
+                        names.sort();
+                        let separator = if one_per_line { "\n" } else { "  " };
+                        self.shell_print(&names.join(separator));
+                    }
+                    Err(_) => self.shell_print(&format!("ls: {}: No such directory", path)),
+                }
+            }
+            "cat" => {
+                for path in args {
+                    match std::fs::read(self.host_path(path)) {
+                        Ok(content) => {
+                            let text = String::from_utf8_lossy(&content).replace('\n', "\r\n");
+                            self.emit(text.as_bytes());
+                        }
+                        Err(_) => self.shell_print(&format!("cat: {}: No such file", path)),
+                    }
+                }
+            }
+            "rm" => {
+                for path in args {
+                    if std::fs::remove_file(self.host_path(path)).is_err() {
+                        self.shell_print(&format!("rm: {}: No such file", path));
+                    }
+                }
+            }
+            "python" => {
+                let Some(path) = args.first() else {
+                    self.shell_print("usage: python <file>");
+                    return None;
+                };
+                let code = match std::fs::read(self.host_path(path)) {
+                    Ok(code) => code,
+                    Err(_) => {
+                        self.shell_print(&format!("python: {}: No such file", path));
+                        return None;
+                    }
+                };
+                if let Err(e) = self.run_code(&code, Resume::Shell) {
+                    self.shell_print(&format!("python: {}", e));
+                    return None;
+                }
+                return Some(State::Running {
+                    resume: Resume::Shell,
+                });
+            }
+            "fileup" => {
+                let Some(path) = args.first() else {
+                    self.shell_print("usage: fileup <file>");
+                    return None;
+                };
+                self.shell_print("Send CTRL+D to end upload");
+                self.emit(b">");
+                return Some(State::Fileup {
+                    path: path.to_string(),
+                    line: vec![],
+                    content: vec![],
+                });
+            }
+            _ => self.shell_print(&format!("{}: command not found", name)),
+        }
+        None
+    }
+}
+
+/// Total size of the files below `dir`
+fn dir_size(dir: &Path) -> u64 {
+    std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.metadata() {
+            Ok(meta) if meta.is_dir() => dir_size(&entry.path()),
+            Ok(meta) => meta.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+impl Read for SimDevice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let running = matches!(self.state, State::Running { .. });
+        // Only wait for the worker when there is nothing to return yet
+        self.pump((running && self.output.is_empty()).then_some(READ_TIMEOUT));
+
+        if self.output.is_empty() {
+            if !running {
+                // Nothing will arrive until more input is written
+                thread::sleep(Duration::from_millis(10));
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Operation timed out",
+            ));
+        }
+
+        let n = buf.len().min(self.output.len());
+        for (dst, src) in buf.iter_mut().zip(self.output.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for SimDevice {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            // Let a finished program hand the prompt back before more input
+            self.pump(None);
+            self.feed(byte)?;
+        }
+        if !self.input.is_empty() {
+            let input = std::mem::take(&mut self.input);
+            self.worker()?.send(b'I', &input)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for SimDevice {
+    fn bytes_to_read(&mut self) -> Result<usize> {
+        self.pump(None);
+        Ok(self.output.len())
+    }
+}
+
+/// Serves a simulated device over TCP, one connection at a time
+pub fn serve(mode: SimMode, root: &Path, listen: &str) -> Result<()> {
+    let listener =
+        TcpListener::bind(listen).with_context(|| format!("Could not listen on {}", listen))?;
+    let addr = listener.local_addr()?;
+    println!(
+        "Simulated {} device on {} (root {}). Connect with -p tcp://{}",
+        mode.description(),
+        addr,
+        root.display(),
+        addr
+    );
+
+    for stream in listener.incoming() {
+        let mut stream = stream?;
+        stream.set_read_timeout(Some(Duration::from_millis(10)))?;
+        let mut device = SimDevice::new(mode, root)?;
+        let mut buf = [0u8; 1024];
+
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => device.write_all(&buf[..n])?,
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(_) => break,
+            }
+
+            match device.read(&mut buf) {
+                Ok(n) => {
+                    if stream.write_all(&buf[..n]).is_err() {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// True, with a note on stderr, when there is no python3 for device code
+#[cfg(test)]
+pub(crate) fn python3_missing() -> bool {
+    let missing = Command::new("python3").arg("--version").output().is_err();
+    if missing {
+        eprintln!("Skipping: python3 is needed to run the simulated device");
+    }
+    missing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(mode: SimMode, name: &str) -> SimDevice {
+        let root = std::env::temp_dir().join(format!(
+            "upyremote-sim-unit-{}-{}",
+            std::process::id(),
+            name
+        ));
+        SimDevice::new(mode, &root).unwrap()
+    }
+
+    fn take_output(device: &mut SimDevice) -> Vec<u8> {
+        device.output.drain(..).collect()
+    }
+
+    #[test]
+    fn raw_paste_request_is_accepted() {
+        let mut device = device(SimMode::Repl, "raw-paste");
+        device.write_all(b"\x01").unwrap();
+        assert!(take_output(&mut device).ends_with(RAW_BANNER.as_bytes()));
+
+        device.write_all(b"\x05A\x01").unwrap();
+        let mut expected = b"R\x01".to_vec();
+        expected.extend_from_slice(&(RAW_PASTE_WINDOW as u16).to_le_bytes());
+        assert_eq!(take_output(&mut device), expected);
+        assert!(matches!(device.state, State::RawPaste { .. }));
+
+        // Flow control: one Ctrl-A per window received
+        device.write_all(&[b'#'; RAW_PASTE_WINDOW]).unwrap();
+        assert_eq!(take_output(&mut device), b"\x01");
+    }
+
+    #[test]
+    fn raw_paste_request_is_refused_by_legacy_firmware() {
+        let mut device = device(SimMode::ReplLegacy, "raw-paste-legacy");
+        device.write_all(b"\x01").unwrap();
+        take_output(&mut device);
+
+        device.write_all(b"\x05A\x01").unwrap();
+        assert_eq!(take_output(&mut device), b"R\x00");
+        assert!(matches!(device.state, State::Raw { ref code } if code.is_empty()));
+    }
+}
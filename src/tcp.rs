@@ -0,0 +1,345 @@
+//! Plain TCP and telnet transports, for boards exposed through ser2net or
+//! upyOS's own telnet server.
+//!
+//! Raw TCP passes bytes through untouched. Telnet strips IAC command
+//! sequences from the incoming stream, answers option negotiation and
+//! escapes 0xFF bytes on the way out.
+
+use anyhow::{Context, Result};
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::TcpStream,
+};
+
+use crate::transport::{READ_TIMEOUT, Transport};
+
+const TELNET_DEFAULT_PORT: u16 = 23;
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+const OPT_ECHO: u8 = 1;
+const OPT_SGA: u8 = 3;
+
+/// Position of the telnet decoder within the incoming byte stream
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TelnetState {
+    Data,
+    Iac,
+    /// IAC followed by WILL/WONT/DO/DONT, waiting for the option byte
+    Negotiate(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+pub struct TcpTransport {
+    stream: TcpStream,
+    /// Telnet decoder state, `None` for raw TCP
+    telnet: Option<TelnetState>,
+    /// Received data not yet consumed
+    pending: VecDeque<u8>,
+}
+
+/// Splits `host:port` from a `scheme://host[:port]` URL
+fn parse_host_port(url: &str, scheme: &str, default_port: Option<u16>) -> Result<(String, u16)> {
+    let rest = url
+        .strip_prefix(scheme)
+        .with_context(|| format!("Invalid URL '{}'", url))?;
+    let authority = rest.split('/').next().unwrap_or_default();
+
+    let (host, port) = match (authority.rsplit_once(':'), default_port) {
+        (Some((host, port)), _) => (
+            host,
+            port.parse()
+                .with_context(|| format!("Invalid port in '{}'", url))?,
+        ),
+        (None, Some(port)) => (authority, port),
+        (None, None) => anyhow::bail!("Missing port in '{}' (expected {}host:port)", url, scheme),
+    };
+
+    if host.is_empty() {
+        anyhow::bail!("Missing host in '{}'", url);
+    }
+    Ok((host.to_string(), port))
+}
+
+impl TcpTransport {
+    /// Connects to `tcp://host:port`
+    pub fn connect_raw(url: &str) -> Result<Self> {
+        let (host, port) = parse_host_port(url, "tcp://", None)?;
+        Self::connect(&host, port, false)
+    }
+
+    /// Connects to `telnet://host[:port]`
+    pub fn connect_telnet(url: &str) -> Result<Self> {
+        let (host, port) = parse_host_port(url, "telnet://", Some(TELNET_DEFAULT_PORT))?;
+        Self::connect(&host, port, true)
+    }
+
+    fn connect(host: &str, port: u16, telnet: bool) -> Result<Self> {
+        let stream = TcpStream::connect((host, port))
+            .with_context(|| format!("Could not connect to {}:{}", host, port))?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+
+        Ok(TcpTransport {
+            stream,
+            telnet: telnet.then_some(TelnetState::Data),
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Reads from the socket into `pending`, decoding telnet commands.
+    /// Returns false when no data arrived within the timeout.
+    fn receive(&mut self) -> io::Result<bool> {
+        let mut buf = [0u8; 1024];
+        let n = match self.stream.read(&mut buf) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Connection closed by remote host",
+                ));
+            }
+            Ok(n) => n,
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                return Ok(false);
+            }
+            Err(e) => return Err(e),
+        };
+
+        if self.telnet.is_none() {
+            self.pending.extend(&buf[..n]);
+            return Ok(true);
+        }
+
+        let mut replies = Vec::new();
+        for &byte in &buf[..n] {
+            self.decode_telnet(byte, &mut replies);
+        }
+        if !replies.is_empty() {
+            self.stream.write_all(&replies)?;
+        }
+        Ok(true)
+    }
+
+    fn decode_telnet(&mut self, byte: u8, replies: &mut Vec<u8>) {
+        let Some(state) = self.telnet else {
+            return;
+        };
+
+        let next = match (state, byte) {
+            (TelnetState::Data, IAC) => TelnetState::Iac,
+            (TelnetState::Data, _) => {
+                self.pending.push_back(byte);
+                TelnetState::Data
+            }
+            // Escaped 0xFF data byte
+            (TelnetState::Iac, IAC) => {
+                self.pending.push_back(IAC);
+                TelnetState::Data
+            }
+            (TelnetState::Iac, WILL | WONT | DO | DONT) => TelnetState::Negotiate(byte),
+            (TelnetState::Iac, SB) => TelnetState::Subnegotiation,
+            // Other two-byte commands (NOP, GA, ...) carry no data
+            (TelnetState::Iac, _) => TelnetState::Data,
+            (TelnetState::Negotiate(command), option) => {
+                replies.extend_from_slice(&negotiation_reply(command, option));
+                TelnetState::Data
+            }
+            (TelnetState::Subnegotiation, IAC) => TelnetState::SubnegotiationIac,
+            (TelnetState::Subnegotiation, _) => TelnetState::Subnegotiation,
+            (TelnetState::SubnegotiationIac, SE) => TelnetState::Data,
+            (TelnetState::SubnegotiationIac, _) => TelnetState::Subnegotiation,
+        };
+        self.telnet = Some(next);
+    }
+}
+
+/// Answer to an option request: let the server echo and suppress go-ahead,
+/// refuse everything else. WONT/DONT need no answer.
+fn negotiation_reply(command: u8, option: u8) -> Vec<u8> {
+    match (command, option) {
+        (WILL, OPT_ECHO | OPT_SGA) => vec![IAC, DO, option],
+        (WILL, _) => vec![IAC, DONT, option],
+        (DO, OPT_SGA) => vec![IAC, WILL, option],
+        (DO, _) => vec![IAC, WONT, option],
+        _ => vec![],
+    }
+}
+
+impl Read for TcpTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            self.receive()?;
+        }
+        if self.pending.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Operation timed out",
+            ));
+        }
+
+        let n = buf.len().min(self.pending.len());
+        for (dst, src) in buf.iter_mut().zip(self.pending.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for TcpTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.telnet.is_some() && buf.contains(&IAC) {
+            // 0xFF must be doubled so it isn't taken for a command
+            let escaped: Vec<u8> = buf
+                .iter()
+                .flat_map(|&b| if b == IAC { vec![IAC, IAC] } else { vec![b] })
+                .collect();
+            self.stream.write_all(&escaped)?;
+        } else {
+            self.stream.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Transport for TcpTransport {
+    fn bytes_to_read(&mut self) -> Result<usize> {
+        if self.pending.is_empty() {
+            self.stream.set_nonblocking(true)?;
+            let received = self.receive();
+            self.stream.set_nonblocking(false)?;
+            received?;
+        }
+        Ok(self.pending.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        net::TcpListener,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    /// Client transport connected to a local server socket
+    fn connect(telnet: bool) -> (TcpTransport, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = if telnet {
+            TcpTransport::connect_telnet(&format!("telnet://{}", addr))
+        } else {
+            TcpTransport::connect_raw(&format!("tcp://{}", addr))
+        };
+        let (server, _) = listener.accept().unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        (client.unwrap(), server)
+    }
+
+    /// Reads until `len` data bytes have arrived
+    fn read_data(transport: &mut TcpTransport, len: usize) -> Vec<u8> {
+        let start = Instant::now();
+        let mut data = Vec::new();
+        let mut buf = [0u8; 64];
+        while data.len() < len {
+            assert!(start.elapsed() < Duration::from_secs(5), "got {:?}", data);
+            match transport.read(&mut buf) {
+                Ok(n) => data.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => panic!("{}", e),
+            }
+        }
+        data
+    }
+
+    fn read_exact(server: &mut TcpStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        server.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn parses_urls() {
+        assert_eq!(
+            parse_host_port("telnet://board.local", "telnet://", Some(23)).unwrap(),
+            ("board.local".to_string(), 23)
+        );
+        assert_eq!(
+            parse_host_port("tcp://10.0.0.2:4000/", "tcp://", None).unwrap(),
+            ("10.0.0.2".to_string(), 4000)
+        );
+        assert!(parse_host_port("tcp://10.0.0.2", "tcp://", None).is_err());
+        assert!(parse_host_port("tcp://:4000", "tcp://", None).is_err());
+    }
+
+    #[test]
+    fn negotiation_replies() {
+        assert_eq!(negotiation_reply(WILL, OPT_ECHO), [IAC, DO, OPT_ECHO]);
+        assert_eq!(negotiation_reply(WILL, OPT_SGA), [IAC, DO, OPT_SGA]);
+        assert_eq!(negotiation_reply(WILL, 24), [IAC, DONT, 24]);
+        assert_eq!(negotiation_reply(DO, OPT_SGA), [IAC, WILL, OPT_SGA]);
+        assert_eq!(negotiation_reply(DO, 31), [IAC, WONT, 31]);
+        assert!(negotiation_reply(WONT, OPT_ECHO).is_empty());
+        assert!(negotiation_reply(DONT, OPT_SGA).is_empty());
+    }
+
+    #[test]
+    fn telnet_unescapes_data_and_answers_options() {
+        let (mut client, mut server) = connect(true);
+        server
+            .write_all(&[b'a', IAC, IAC, b'b', IAC, WILL, OPT_ECHO, IAC, DO, 31, b'c'])
+            .unwrap();
+
+        assert_eq!(read_data(&mut client, 4), [b'a', 0xFF, b'b', b'c']);
+        assert_eq!(
+            read_exact(&mut server, 6),
+            [IAC, DO, OPT_ECHO, IAC, WONT, 31]
+        );
+    }
+
+    #[test]
+    fn telnet_skips_subnegotiation_across_reads() {
+        let (mut client, mut server) = connect(true);
+        server.write_all(&[b'x', IAC, SB, 24, 1, IAC]).unwrap();
+        assert_eq!(read_data(&mut client, 1), b"x");
+
+        thread::sleep(Duration::from_millis(20));
+        // An escaped IAC inside the subnegotiation doesn't end it
+        server.write_all(&[IAC, 7, IAC, SE, b'y']).unwrap();
+        assert_eq!(read_data(&mut client, 1), b"y");
+        assert_eq!(client.telnet, Some(TelnetState::Data));
+    }
+
+    #[test]
+    fn telnet_doubles_iac_on_write() {
+        let (mut client, mut server) = connect(true);
+        client.write_all(&[1, 0xFF, 2]).unwrap();
+        assert_eq!(read_exact(&mut server, 4), [1, IAC, IAC, 2]);
+    }
+
+    #[test]
+    fn raw_tcp_passes_bytes_through() {
+        let (mut client, mut server) = connect(false);
+        client.write_all(&[1, 0xFF, 2]).unwrap();
+        assert_eq!(read_exact(&mut server, 3), [1, 0xFF, 2]);
+
+        server.write_all(&[IAC, WILL, OPT_ECHO]).unwrap();
+        assert_eq!(read_data(&mut client, 3), [IAC, WILL, OPT_ECHO]);
+    }
+}
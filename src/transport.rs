@@ -0,0 +1,200 @@
+//! Byte-stream transports used to talk to a device.
+//!
+//! `MpDevice` only needs something it can read from and write to; the
+//! transport decides whether that is a USB serial port or a network link.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serialport::{DataBits, FlowControl, Parity, StopBits};
+use std::{
+    fmt,
+    io::{Read, Write},
+    str::FromStr,
+    thread,
+    time::Duration,
+};
+
+use crate::progress::Progress;
+use crate::simulator::SimDevice;
+use crate::tcp::TcpTransport;
+use crate::webrepl::WebRepl;
+
+/// How long a read waits for data before failing with `TimedOut`.
+/// All transports follow the serial port convention so callers can poll.
+pub const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Rates tried by `--baud auto`, most common first
+pub const AUTO_BAUD_RATES: &[u32] = &[115200, 9600, 19200, 38400, 57600, 230400, 460800, 921600];
+
+/// Serial baud rate, or `auto` to probe the common rates
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "BaudValue")]
+pub enum Baud {
+    Rate(u32),
+    Auto,
+}
+
+impl FromStr for Baud {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("auto") {
+            return Ok(Baud::Auto);
+        }
+        match s.parse::<u32>() {
+            Ok(rate) if rate > 0 => Ok(Baud::Rate(rate)),
+            _ => Err(format!(
+                "invalid baud rate '{}' (expected a number or 'auto')",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Baud {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Baud::Rate(rate) => write!(f, "{}", rate),
+            Baud::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+/// Baud rate as written in the configuration file: `115200` or `"auto"`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BaudValue {
+    Number(u32),
+    Text(String),
+}
+
+impl TryFrom<BaudValue> for Baud {
+    type Error = String;
+
+    fn try_from(value: BaudValue) -> Result<Self, Self::Error> {
+        match value {
+            BaudValue::Number(rate) => Baud::from_str(&rate.to_string()),
+            BaudValue::Text(text) => Baud::from_str(&text),
+        }
+    }
+}
+
+pub trait Transport: Read + Write {
+    /// Number of bytes that can be read without blocking
+    fn bytes_to_read(&mut self) -> Result<usize>;
+
+    /// Resets the board through the connection itself (e.g. DTR/RTS lines)
+    fn hard_reset(&mut self) -> Result<()> {
+        anyhow::bail!("Hard reset is not supported over this connection")
+    }
+
+    /// Whether `put_file` and `get_file` use a file-transfer protocol of the
+    /// transport's own
+    fn has_file_transfer(&self) -> bool {
+        false
+    }
+
+    /// Uploads a file with the transport's own file-transfer protocol.
+    /// Returns `None` when the transport has none and the REPL must be used.
+    fn put_file(
+        &mut self,
+        _remote_path: &str,
+        _data: &[u8],
+        _progress: &mut Progress,
+    ) -> Option<Result<()>> {
+        None
+    }
+
+    /// Downloads a file with the transport's own file-transfer protocol.
+    /// Returns `None` when the transport has none and the REPL must be used.
+    fn get_file(
+        &mut self,
+        _remote_path: &str,
+        _progress: &mut Progress,
+    ) -> Option<Result<Vec<u8>>> {
+        None
+    }
+}
+
+/// Whether the port string names a serial port, where the baud rate matters
+pub fn is_serial(port_name: &str) -> bool {
+    !["ws://", "tcp://", "telnet://", "sim:"]
+        .iter()
+        .any(|scheme| port_name.starts_with(scheme))
+}
+
+/// Opens the transport selected by the port string:
+/// - `ws://[:password@]host[:port]` for WebREPL
+/// - `tcp://host:port` for a raw TCP socket (e.g. ser2net)
+/// - `telnet://host[:port]` for a telnet server (e.g. upyOS)
+/// - `sim:<mode>[:<root>]` for the built-in simulated device
+/// - anything else is a serial port name
+pub fn open(port_name: &str, baud_rate: u32) -> Result<Box<dyn Transport>> {
+    if port_name.starts_with("ws://") {
+        return Ok(Box::new(WebRepl::connect(port_name)?));
+    }
+    if port_name.starts_with("tcp://") {
+        return Ok(Box::new(TcpTransport::connect_raw(port_name)?));
+    }
+    if port_name.starts_with("telnet://") {
+        return Ok(Box::new(TcpTransport::connect_telnet(port_name)?));
+    }
+    if port_name.starts_with("sim:") {
+        return Ok(Box::new(SimDevice::open(port_name)?));
+    }
+
+    Ok(Box::new(SerialTransport::open(port_name, baud_rate)?))
+}
+
+pub struct SerialTransport {
+    port: Box<dyn serialport::SerialPort>,
+}
+
+impl SerialTransport {
+    pub fn open(port_name: &str, baud_rate: u32) -> Result<Self> {
+        let port = serialport::new(port_name, baud_rate)
+            .data_bits(DataBits::Eight)
+            .parity(Parity::None)
+            .stop_bits(StopBits::One)
+            .flow_control(FlowControl::None)
+            .timeout(READ_TIMEOUT)
+            .open()
+            .with_context(|| format!("Could not open port {}", port_name))?;
+
+        Ok(SerialTransport { port })
+    }
+}
+
+impl Read for SerialTransport {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.port.read(buf)
+    }
+}
+
+impl Write for SerialTransport {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.port.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.port.flush()
+    }
+}
+
+impl Transport for SerialTransport {
+    fn bytes_to_read(&mut self) -> Result<usize> {
+        Ok(self.port.bytes_to_read()? as usize)
+    }
+
+    fn hard_reset(&mut self) -> Result<()> {
+        // Toggle DTR/RTS for hard reset on many ESP32 boards
+        self.port.write_data_terminal_ready(true)?;
+        self.port.write_request_to_send(false)?;
+        thread::sleep(Duration::from_millis(100));
+        self.port.write_data_terminal_ready(false)?;
+        self.port.write_request_to_send(true)?;
+        thread::sleep(Duration::from_millis(100));
+        self.port.write_request_to_send(false)?;
+        Ok(())
+    }
+}
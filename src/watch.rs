@@ -0,0 +1,82 @@
+//! Change detection for `watch`: the files of a local directory are polled
+//! for a new size or modification time, which works on every platform and
+//! for every way an editor saves a file.
+
+use anyhow::Result;
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{glob::PathFilter, scan_local_tree};
+
+/// Minimum time between two looks at the files
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Wait after a change, so that files saved together are uploaded together
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+/// Size and modification time of each file, by path relative to the directory
+type Snapshot = BTreeMap<String, (u64, SystemTime)>;
+
+pub struct Watcher<'a> {
+    dir: &'a Path,
+    filter: &'a PathFilter,
+    known: Snapshot,
+    last_poll: Instant,
+}
+
+impl<'a> Watcher<'a> {
+    /// Starts from the current state of the files below `dir`
+    pub fn new(dir: &'a Path, filter: &'a PathFilter) -> Result<Self> {
+        Ok(Watcher {
+            dir,
+            filter,
+            known: snapshot(dir, filter)?,
+            last_poll: Instant::now(),
+        })
+    }
+
+    /// Whether a file was added or changed since the last `take_changes`.
+    /// Looks at the files at most once per `POLL_INTERVAL`.
+    pub fn poll(&mut self) -> bool {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return false;
+        }
+        self.last_poll = Instant::now();
+        // A directory that can't be read in the middle of a save is looked at again later
+        snapshot(self.dir, self.filter).is_ok_and(|now| !changed(&self.known, &now).is_empty())
+    }
+
+    /// Files added or changed since the last call, which become the known
+    /// state. Deleted files are forgotten.
+    pub fn take_changes(&mut self) -> Result<Vec<String>> {
+        thread::sleep(SETTLE_TIME);
+        let now = snapshot(self.dir, self.filter)?;
+        let files = changed(&self.known, &now);
+        self.known = now;
+        Ok(files)
+    }
+}
+
+fn snapshot(dir: &Path, filter: &PathFilter) -> Result<Snapshot> {
+    let (_, files) = scan_local_tree(dir, filter)?;
+    let mut snapshot = Snapshot::new();
+    for file in files {
+        // A file removed since the scan, such as an editor's temporary file, is left out
+        if let Ok(meta) = std::fs::metadata(dir.join(&file))
+            && let Ok(modified) = meta.modified()
+        {
+            snapshot.insert(file, (meta.len(), modified));
+        }
+    }
+    Ok(snapshot)
+}
+
+fn changed(before: &Snapshot, now: &Snapshot) -> Vec<String> {
+    now.iter()
+        .filter(|(file, state)| before.get(*file) != Some(*state))
+        .map(|(file, _)| file.clone())
+        .collect()
+}
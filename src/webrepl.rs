@@ -0,0 +1,735 @@
+//! WebREPL client: MicroPython's REPL over a WebSocket, as served by the
+//! `webrepl` module on ESP8266/ESP32 boards (default port 8266).
+//!
+//! Terminal traffic travels in text frames. File transfers use binary frames
+//! carrying the WebREPL request/response records (`WA`/`WB`).
+
+use anyhow::{Context, Result};
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, Read, Write},
+    net::TcpStream,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::progress::Progress;
+use crate::transport::{READ_TIMEOUT, Transport};
+
+const DEFAULT_PORT: u16 = 8266;
+const ENV_PASSWORD_VAR: &str = "UPYREMOTE_WEBREPL_PASSWORD";
+
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+
+const WEBREPL_PUT_FILE: u8 = 1;
+const WEBREPL_GET_FILE: u8 = 2;
+/// Maximum length of a file name in a WebREPL request
+const WEBREPL_NAME_LEN: usize = 64;
+const WEBREPL_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct WebRepl {
+    stream: TcpStream,
+    /// Raw bytes received from the socket that don't form a full frame yet
+    rx: Vec<u8>,
+    /// Terminal output (text frames) not yet consumed
+    text: VecDeque<u8>,
+    /// File-transfer data (binary frames) not yet consumed
+    binary: VecDeque<u8>,
+    mask_seed: u32,
+}
+
+/// Parts of a `ws://[:password@]host[:port]` URL
+struct WebReplUrl {
+    host: String,
+    port: u16,
+    password: Option<String>,
+}
+
+fn parse_url(url: &str) -> Result<WebReplUrl> {
+    let rest = url
+        .strip_prefix("ws://")
+        .with_context(|| format!("Invalid WebREPL URL '{}'", url))?;
+    let authority = rest.split('/').next().unwrap_or_default();
+
+    let (password, hostport) = match authority.rsplit_once('@') {
+        Some((userinfo, hostport)) => {
+            let password = userinfo.split_once(':').map_or(userinfo, |(_, p)| p);
+            (Some(password.to_string()), hostport)
+        }
+        None => (None, authority),
+    };
+
+    let (host, port) = match hostport.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse()
+                .with_context(|| format!("Invalid port in WebREPL URL '{}'", url))?,
+        ),
+        None => (hostport, DEFAULT_PORT),
+    };
+
+    if host.is_empty() {
+        anyhow::bail!("Missing host in WebREPL URL '{}'", url);
+    }
+
+    Ok(WebReplUrl {
+        host: host.to_string(),
+        port,
+        password,
+    })
+}
+
+/// Password from the URL, then UPYREMOTE_WEBREPL_PASSWORD, then asked on the terminal
+fn resolve_password(password: Option<String>) -> Result<String> {
+    if let Some(password) = password.or_else(|| std::env::var(ENV_PASSWORD_VAR).ok()) {
+        return Ok(password);
+    }
+
+    eprint!("WebREPL password: ");
+    io::stderr().flush()?;
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+impl WebRepl {
+    pub fn connect(url: &str) -> Result<Self> {
+        let url = parse_url(url)?;
+        let stream = TcpStream::connect((url.host.as_str(), url.port))
+            .with_context(|| format!("Could not connect to {}:{}", url.host, url.port))?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0x5eed);
+
+        let mut repl = WebRepl {
+            stream,
+            rx: Vec::new(),
+            text: VecDeque::new(),
+            binary: VecDeque::new(),
+            mask_seed: seed | 1,
+        };
+
+        repl.handshake(&url.host, url.port)?;
+        repl.login(url.password)?;
+        Ok(repl)
+    }
+
+    fn handshake(&mut self, host: &str, port: u16) -> Result<()> {
+        let key: Vec<u8> = (0..16).map(|_| self.next_random() as u8).collect();
+        let request = format!(
+            "GET / HTTP/1.1\r\n\
+             Host: {}:{}\r\n\
+             Connection: Upgrade\r\n\
+             Upgrade: websocket\r\n\
+             Sec-WebSocket-Key: {}\r\n\
+             Sec-WebSocket-Version: 13\r\n\r\n",
+            host,
+            port,
+            crate::base64_encode(&key)
+        );
+        self.stream.write_all(request.as_bytes())?;
+
+        // Read the HTTP response headers
+        let start = Instant::now();
+        let header_end = loop {
+            if let Some(pos) = self.rx.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+            if start.elapsed() > WEBREPL_TIMEOUT {
+                anyhow::bail!("Timeout during WebSocket handshake");
+            }
+            self.fill()?;
+        };
+
+        let headers = String::from_utf8_lossy(&self.rx[..header_end]).to_string();
+        self.rx.drain(..header_end);
+
+        let status = headers.lines().next().unwrap_or_default();
+        if !status.contains(" 101") {
+            anyhow::bail!("WebSocket handshake rejected: {}", status);
+        }
+        Ok(())
+    }
+
+    fn login(&mut self, password: Option<String>) -> Result<()> {
+        // The board asks for the password before anything else
+        self.read_text_until(&["Password:"], WEBREPL_TIMEOUT)
+            .context("WebREPL did not ask for a password")?;
+
+        let password = resolve_password(password)?;
+        self.write_frame(OP_TEXT, format!("{}\r", password).as_bytes())?;
+
+        let reply =
+            self.read_text_until(&["WebREPL connected", "Access denied"], WEBREPL_TIMEOUT)?;
+        if reply.contains("Access denied") {
+            anyhow::bail!("WebREPL login failed: access denied");
+        }
+        Ok(())
+    }
+
+    /// Cheap xorshift generator for the handshake key and frame masks
+    fn next_random(&mut self) -> u32 {
+        let mut x = self.mask_seed;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.mask_seed = x;
+        x
+    }
+
+    /// Reads terminal output until one of the markers appears
+    fn read_text_until(&mut self, markers: &[&str], timeout: Duration) -> Result<String> {
+        let start = Instant::now();
+        let mut received = Vec::new();
+        loop {
+            received.extend(self.text.drain(..));
+            let text = String::from_utf8_lossy(&received);
+            if markers.iter().any(|m| text.contains(m)) {
+                return Ok(text.to_string());
+            }
+            if start.elapsed() > timeout {
+                anyhow::bail!("Timeout waiting for WebREPL (got {:?})", text);
+            }
+            self.receive()?;
+        }
+    }
+
+    /// Reads whatever is available on the socket into `rx`.
+    /// Returns false when the read timed out without data.
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut buf = [0u8; 2048];
+        match self.stream.read(&mut buf) {
+            Ok(0) => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "WebREPL connection closed",
+            )),
+            Ok(n) => {
+                self.rx.extend_from_slice(&buf[..n]);
+                Ok(true)
+            }
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Waits up to the read timeout for data and sorts complete frames into
+    /// the text and binary queues
+    fn receive(&mut self) -> io::Result<()> {
+        self.fill()?;
+        self.process_frames()
+    }
+
+    fn process_frames(&mut self) -> io::Result<()> {
+        while let Some((opcode, payload)) = self.next_frame() {
+            match opcode {
+                OP_TEXT => self.text.extend(payload),
+                OP_BINARY => self.binary.extend(payload),
+                OP_PING => self.write_frame(0xA, &payload)?,
+                OP_CLOSE => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "WebREPL connection closed by device",
+                    ));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes one complete frame out of `rx`, if there is one
+    fn next_frame(&mut self) -> Option<(u8, Vec<u8>)> {
+        if self.rx.len() < 2 {
+            return None;
+        }
+        let opcode = self.rx[0] & 0x0f;
+        let masked = self.rx[1] & 0x80 != 0;
+        let (len, mut pos) = match self.rx[1] & 0x7f {
+            126 => {
+                if self.rx.len() < 4 {
+                    return None;
+                }
+                (u16::from_be_bytes([self.rx[2], self.rx[3]]) as usize, 4)
+            }
+            127 => {
+                if self.rx.len() < 10 {
+                    return None;
+                }
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(&self.rx[2..10]);
+                (u64::from_be_bytes(bytes) as usize, 10)
+            }
+            n => (n as usize, 2),
+        };
+
+        let mask = if masked {
+            if self.rx.len() < pos + 4 {
+                return None;
+            }
+            let mask = [
+                self.rx[pos],
+                self.rx[pos + 1],
+                self.rx[pos + 2],
+                self.rx[pos + 3],
+            ];
+            pos += 4;
+            Some(mask)
+        } else {
+            None
+        };
+
+        if self.rx.len() < pos + len {
+            return None;
+        }
+
+        let mut payload: Vec<u8> = self.rx.drain(..pos + len).skip(pos).collect();
+        if let Some(mask) = mask {
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+        }
+        Some((opcode, payload))
+    }
+
+    /// Sends one masked frame, as required for client-to-server traffic
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 14);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+
+        let mask = self.next_random().to_be_bytes();
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+
+        self.stream.write_all(&frame)?;
+        self.stream.flush()
+    }
+
+    /// Reads exactly `len` bytes of file-transfer data
+    fn read_binary(&mut self, len: usize) -> Result<Vec<u8>> {
+        let start = Instant::now();
+        while self.binary.len() < len {
+            if start.elapsed() > WEBREPL_TIMEOUT {
+                anyhow::bail!("Timeout waiting for WebREPL file transfer data");
+            }
+            self.receive()?;
+        }
+        Ok(self.binary.drain(..len).collect())
+    }
+
+    /// Reads a `WB` response record and checks its status code
+    fn read_response(&mut self) -> Result<()> {
+        let resp = self.read_binary(4)?;
+        if &resp[..2] != b"WB" {
+            anyhow::bail!("Invalid WebREPL response: {:?}", resp);
+        }
+        match u16::from_le_bytes([resp[2], resp[3]]) {
+            0 => Ok(()),
+            code => anyhow::bail!("WebREPL file transfer failed (error {})", code),
+        }
+    }
+
+    /// Sends a `WA` request record: opcode, offset, size and file name
+    fn send_request(&mut self, op: u8, size: u32, remote_path: &str) -> Result<()> {
+        let name = remote_path.as_bytes();
+        if name.len() > WEBREPL_NAME_LEN {
+            anyhow::bail!(
+                "Remote path '{}' is too long for WebREPL (max {} bytes)",
+                remote_path,
+                WEBREPL_NAME_LEN
+            );
+        }
+
+        let mut record = Vec::with_capacity(82);
+        record.extend_from_slice(b"WA");
+        record.push(op);
+        record.push(0);
+        record.extend_from_slice(&0u64.to_le_bytes());
+        record.extend_from_slice(&size.to_le_bytes());
+        record.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        record.extend_from_slice(name);
+        record.resize(82, 0);
+
+        self.write_frame(OP_BINARY, &record)?;
+        Ok(())
+    }
+
+    fn upload(&mut self, remote_path: &str, data: &[u8], progress: &mut Progress) -> Result<()> {
+        let size = u32::try_from(data.len()).context("File too large for WebREPL")?;
+        self.send_request(WEBREPL_PUT_FILE, size, remote_path)?;
+        self.read_response()?;
+
+        for chunk in data.chunks(1024) {
+            self.write_frame(OP_BINARY, chunk)?;
+            progress.add(chunk.len());
+        }
+        self.read_response()
+    }
+
+    /// The protocol doesn't announce the file size, so progress has no total
+    fn download(&mut self, remote_path: &str, progress: &mut Progress) -> Result<Vec<u8>> {
+        self.send_request(WEBREPL_GET_FILE, 0, remote_path)?;
+        self.read_response()?;
+
+        let mut data = Vec::new();
+        loop {
+            // Each zero byte asks the board for the next block
+            self.write_frame(OP_BINARY, b"\0")?;
+            let header = self.read_binary(2)?;
+            let len = u16::from_le_bytes([header[0], header[1]]) as usize;
+            if len == 0 {
+                break;
+            }
+            data.extend(self.read_binary(len)?);
+            progress.set(data.len());
+        }
+
+        self.read_response()?;
+        Ok(data)
+    }
+}
+
+impl Read for WebRepl {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.text.is_empty() {
+            self.receive()?;
+        }
+        if self.text.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Operation timed out",
+            ));
+        }
+
+        let n = buf.len().min(self.text.len());
+        for (dst, src) in buf.iter_mut().zip(self.text.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for WebRepl {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_frame(OP_TEXT, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Transport for WebRepl {
+    fn bytes_to_read(&mut self) -> Result<usize> {
+        if self.text.is_empty() {
+            self.stream.set_nonblocking(true)?;
+            let filled = self.fill();
+            self.stream.set_nonblocking(false)?;
+            filled?;
+            self.process_frames()?;
+        }
+        Ok(self.text.len())
+    }
+
+    fn has_file_transfer(&self) -> bool {
+        true
+    }
+
+    fn put_file(
+        &mut self,
+        remote_path: &str,
+        data: &[u8],
+        progress: &mut Progress,
+    ) -> Option<Result<()>> {
+        Some(self.upload(remote_path, data, progress))
+    }
+
+    fn get_file(&mut self, remote_path: &str, progress: &mut Progress) -> Option<Result<Vec<u8>>> {
+        Some(self.download(remote_path, progress))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    /// Server side of a stand-in WebREPL board
+    struct Board {
+        stream: TcpStream,
+        rx: Vec<u8>,
+    }
+
+    impl Board {
+        /// Accepts the client's upgrade request and asks for the password
+        fn accept(listener: &TcpListener) -> Self {
+            let (stream, _) = listener.accept().unwrap();
+            let mut board = Board {
+                stream,
+                rx: Vec::new(),
+            };
+            while !board.rx.windows(4).any(|w| w == b"\r\n\r\n") {
+                board.fill();
+            }
+            let request = String::from_utf8_lossy(&board.rx).to_string();
+            assert!(request.starts_with("GET / HTTP/1.1\r\n"));
+            assert!(request.contains("Upgrade: websocket\r\n"));
+            assert!(request.contains("Sec-WebSocket-Key: "));
+            board.rx.clear();
+            board
+                .stream
+                .write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n")
+                .unwrap();
+            board.send(OP_TEXT, b"Password: ");
+            board
+        }
+
+        fn fill(&mut self) {
+            let mut buf = [0u8; 2048];
+            let n = self.stream.read(&mut buf).unwrap();
+            assert!(n > 0, "client closed the connection");
+            self.rx.extend_from_slice(&buf[..n]);
+        }
+
+        /// Reads one client frame, which must be masked
+        fn recv(&mut self) -> (u8, Vec<u8>) {
+            loop {
+                if self.rx.len() >= 2 {
+                    assert_eq!(self.rx[1] & 0x80, 0x80, "client frame not masked");
+                    let (len, pos) = match self.rx[1] & 0x7f {
+                        126 if self.rx.len() >= 4 => {
+                            (u16::from_be_bytes([self.rx[2], self.rx[3]]) as usize, 4)
+                        }
+                        126 => (usize::MAX, 0),
+                        n => (n as usize, 2),
+                    };
+                    if len != usize::MAX && self.rx.len() >= pos + 4 + len {
+                        let opcode = self.rx[0] & 0x0f;
+                        let mask = [
+                            self.rx[pos],
+                            self.rx[pos + 1],
+                            self.rx[pos + 2],
+                            self.rx[pos + 3],
+                        ];
+                        let frame: Vec<u8> = self.rx.drain(..pos + 4 + len).collect();
+                        let payload = frame[pos + 4..]
+                            .iter()
+                            .enumerate()
+                            .map(|(i, b)| b ^ mask[i % 4])
+                            .collect();
+                        return (opcode, payload);
+                    }
+                }
+                self.fill();
+            }
+        }
+
+        /// Unmasked server frame
+        fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+            let mut frame = vec![0x80 | opcode];
+            if payload.len() < 126 {
+                frame.push(payload.len() as u8);
+            } else {
+                frame.push(126);
+                frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+            }
+            frame.extend_from_slice(payload);
+            frame
+        }
+
+        fn send(&mut self, opcode: u8, payload: &[u8]) {
+            self.stream
+                .write_all(&Self::frame(opcode, payload))
+                .unwrap();
+        }
+
+        /// Sends bytes a few at a time so frames span several client reads
+        fn send_split(&mut self, bytes: &[u8]) {
+            for piece in bytes.chunks(3) {
+                self.stream.write_all(piece).unwrap();
+                self.stream.flush().unwrap();
+                thread::sleep(Duration::from_millis(2));
+            }
+        }
+
+        fn login(&mut self, password: &str) {
+            let (opcode, payload) = self.recv();
+            assert_eq!(opcode, OP_TEXT);
+            if payload == format!("{}\r", password).as_bytes() {
+                self.send(OP_TEXT, b"\r\nWebREPL connected\r\n>>> ");
+            } else {
+                self.send(OP_TEXT, b"\r\nAccess denied\r\n");
+            }
+        }
+
+        /// Reads a WA request record: opcode, size and file name
+        fn request(&mut self) -> (u8, u32, String) {
+            let (opcode, record) = self.recv();
+            assert_eq!(opcode, OP_BINARY);
+            assert_eq!(record.len(), 82);
+            assert_eq!(&record[..2], b"WA");
+            assert_eq!(&record[4..12], &[0; 8]);
+            let size = u32::from_le_bytes(record[12..16].try_into().unwrap());
+            let name_len = u16::from_le_bytes([record[16], record[17]]) as usize;
+            assert!(record[18 + name_len..].iter().all(|&b| b == 0));
+            let name = String::from_utf8(record[18..18 + name_len].to_vec()).unwrap();
+            (record[2], size, name)
+        }
+
+        fn respond(&mut self, status: u16) {
+            let mut resp = b"WB".to_vec();
+            resp.extend_from_slice(&status.to_le_bytes());
+            self.send(OP_BINARY, &resp);
+        }
+    }
+
+    fn serve(board: impl FnOnce(&TcpListener) + Send + 'static) -> (u16, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || board(&listener));
+        (port, handle)
+    }
+
+    fn no_progress() -> Progress {
+        Progress::new("test", None, false)
+    }
+
+    #[test]
+    fn parses_urls() {
+        let url = parse_url("ws://:secret@192.168.4.1:8000/").unwrap();
+        assert_eq!(url.host, "192.168.4.1");
+        assert_eq!(url.port, 8000);
+        assert_eq!(url.password.as_deref(), Some("secret"));
+
+        let url = parse_url("ws://board.local").unwrap();
+        assert_eq!(url.port, DEFAULT_PORT);
+        assert_eq!(url.password, None);
+
+        assert!(parse_url("ws://:8266").is_err());
+        assert!(parse_url("http://board").is_err());
+    }
+
+    #[test]
+    fn logs_in_and_exchanges_terminal_text() {
+        let (port, board) = serve(|listener| {
+            let mut board = Board::accept(listener);
+            board.login("secret");
+            let (opcode, payload) = board.recv();
+            assert_eq!((opcode, payload.as_slice()), (OP_TEXT, &b"print(1)\r"[..]));
+            // A frame with a 16-bit length, delivered in small pieces
+            let output = "x".repeat(300);
+            board.send_split(&Board::frame(OP_TEXT, output.as_bytes()));
+        });
+
+        let mut repl = WebRepl::connect(&format!("ws://:secret@127.0.0.1:{}", port)).unwrap();
+        repl.write_all(b"print(1)\r").unwrap();
+        let output = repl.read_text_until(&[&"x".repeat(300)], WEBREPL_TIMEOUT);
+        assert!(output.unwrap().ends_with(&"x".repeat(300)));
+        board.join().unwrap();
+    }
+
+    #[test]
+    fn reports_access_denied() {
+        let (port, board) = serve(|listener| {
+            let mut board = Board::accept(listener);
+            board.login("secret");
+        });
+
+        let err = WebRepl::connect(&format!("ws://:wrong@127.0.0.1:{}", port))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("access denied"), "{}", err);
+        board.join().unwrap();
+    }
+
+    #[test]
+    fn rejects_failed_handshake() {
+        let (port, board) = serve(|listener| {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
+                .unwrap();
+        });
+
+        let err = WebRepl::connect(&format!("ws://:secret@127.0.0.1:{}", port))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("handshake rejected"), "{}", err);
+        board.join().unwrap();
+    }
+
+    #[test]
+    fn puts_and_gets_files() {
+        let content: Vec<u8> = (0..3000).map(|i| (i * 7 % 256) as u8).collect();
+        let expected = content.clone();
+        let (port, board) = serve(move |listener| {
+            let mut board = Board::accept(listener);
+            board.login("secret");
+
+            let (op, size, name) = board.request();
+            assert_eq!(
+                (op, size, name.as_str()),
+                (WEBREPL_PUT_FILE, 3000, "/lib/data.bin")
+            );
+            board.respond(0);
+            let mut received = Vec::new();
+            while received.len() < size as usize {
+                let (opcode, chunk) = board.recv();
+                assert_eq!(opcode, OP_BINARY);
+                received.extend(chunk);
+            }
+            assert_eq!(received, expected);
+            board.respond(0);
+
+            let (op, size, name) = board.request();
+            assert_eq!(
+                (op, size, name.as_str()),
+                (WEBREPL_GET_FILE, 0, "/lib/data.bin")
+            );
+            board.respond(0);
+            for block in received.chunks(1000).chain([&[][..]]) {
+                assert_eq!(board.recv(), (OP_BINARY, vec![0]));
+                let mut data = (block.len() as u16).to_le_bytes().to_vec();
+                data.extend_from_slice(block);
+                board.send_split(&Board::frame(OP_BINARY, &data));
+            }
+            board.respond(0);
+
+            let (op, _, name) = board.request();
+            assert_eq!((op, name.as_str()), (WEBREPL_GET_FILE, "/missing"));
+            board.respond(2);
+        });
+
+        let mut repl = WebRepl::connect(&format!("ws://:secret@127.0.0.1:{}", port)).unwrap();
+        repl.upload("/lib/data.bin", &content, &mut no_progress())
+            .unwrap();
+        let data = repl.download("/lib/data.bin", &mut no_progress()).unwrap();
+        assert_eq!(data, content);
+        let err = repl.download("/missing", &mut no_progress()).unwrap_err();
+        assert!(err.to_string().contains("error 2"), "{}", err);
+        board.join().unwrap();
+    }
+}
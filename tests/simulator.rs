@@ -0,0 +1,1203 @@
+//! End-to-end tests of upyremote against the built-in simulated device
+//! (`-p sim:<mode>:<root>`). The simulator runs device code with python3;
+//! without it the tests are skipped.
+
+use std::{
+    fs,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+    sync::{OnceLock, mpsc},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// True, with a note on stderr, when there is no python3 to run the simulator
+fn python3_missing() -> bool {
+    static MISSING: OnceLock<bool> = OnceLock::new();
+    let missing =
+        *MISSING.get_or_init(|| Command::new("python3").arg("--version").output().is_err());
+    if missing {
+        eprintln!("Skipping: python3 is needed to run the simulated device");
+    }
+    missing
+}
+
+/// Fresh, empty device filesystem for one test
+fn device_root(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("upyremote-test-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    root
+}
+
+fn sim_port(mode: &str, root: &Path) -> String {
+    format!("sim:{}:{}", mode, root.display())
+}
+
+fn upyremote(port: &str, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_upyremote"))
+        .args(args)
+        .args(["--port", port])
+        .env("RUST_BACKTRACE", "0")
+        .output()
+        .expect("failed to run upyremote")
+}
+
+/// Runs upyremote in `dir`, with `dir` also standing in for the user's
+/// configuration directory so only the given configuration is seen
+fn upyremote_in(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_upyremote"))
+        .args(args)
+        .current_dir(dir)
+        .env("XDG_CONFIG_HOME", dir)
+        .env_remove("UPYREMOTE_PORT")
+        .env_remove("UPYREMOTE_PROFILE")
+        .env("RUST_BACKTRACE", "0")
+        .output()
+        .expect("failed to run upyremote")
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+/// Bytes covering every value, so encoding problems show up
+fn binary_content(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 % 256) as u8).collect()
+}
+
+#[test]
+fn detects_micropython_repl() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("detect-repl");
+    let out = upyremote(&sim_port("repl", &root), &["ls", "/"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert!(stdout(&out).contains("Detected mode: MicroPython REPL"));
+}
+
+#[test]
+fn detects_upyos() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("detect-upyos");
+    let out = upyremote(&sim_port("upyos", &root), &["send", "pwd"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert!(stdout(&out).contains("Detected mode: upyOS"));
+}
+
+#[test]
+fn simulated_device_served_over_tcp() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("tcp");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_upyremote"))
+        .args([
+            "simulate",
+            "-r",
+            root.to_str().unwrap(),
+            "-l",
+            "127.0.0.1:0",
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to run upyremote");
+    let mut banner = String::new();
+    BufReader::new(child.stdout.take().unwrap())
+        .read_line(&mut banner)
+        .unwrap();
+    let port = banner
+        .split_once("-p ")
+        .map(|(_, url)| url.trim().to_string())
+        .expect("no address from simulate");
+    assert!(port.starts_with("tcp://127.0.0.1:"), "{}", port);
+
+    let local = root.with_extension("bin");
+    let content = binary_content(2000);
+    fs::write(&local, &content).unwrap();
+    let out = upyremote(&port, &["put", local.to_str().unwrap(), "/data.bin"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert_eq!(fs::read(root.join("data.bin")).unwrap(), content);
+
+    // The simulator takes the next connection once the previous one closed
+    let out = upyremote(
+        &port,
+        &["exec", "print(len(open('/data.bin', 'rb').read()))"],
+    );
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert!(stdout(&out).contains("2000"));
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+#[test]
+fn exec_prints_output() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("exec");
+    let out = upyremote(&sim_port("repl", &root), &["exec", "print(6 * 7)"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert!(stdout(&out).contains("42"));
+}
+
+#[test]
+fn exec_exception_fails_with_traceback() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("exec-exception");
+    let out = upyremote(&sim_port("repl", &root), &["exec", "print('before')\n1/0"]);
+    assert!(!out.status.success());
+    assert!(stdout(&out).contains("before"));
+    assert!(stderr(&out).contains("Traceback"));
+    assert!(stderr(&out).contains("ZeroDivisionError"));
+}
+
+#[test]
+fn exec_requires_repl_mode() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("exec-upyos");
+    let out = upyremote(&sim_port("upyos", &root), &["exec", "print(1)"]);
+    assert!(!out.status.success());
+    assert!(stderr(&out).contains("requires MicroPython REPL mode"));
+}
+
+#[test]
+fn run_executes_file() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("run");
+    let script = root.with_extension("py");
+    fs::write(&script, "for i in range(3):\n    print('line', i)\n").unwrap();
+
+    let out = upyremote(&sim_port("repl", &root), &["run", script.to_str().unwrap()]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert!(stdout(&out).contains("line 2"));
+}
+
+#[test]
+fn mount_runs_script_from_local_directory() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("mount");
+    let local = root.with_extension("local");
+    let _ = fs::remove_dir_all(&local);
+    fs::create_dir_all(local.join("lib")).unwrap();
+    fs::write(
+        local.join("helper.py"),
+        "def greet(name):\n    return 'hello ' + name\n",
+    )
+    .unwrap();
+    // Includes Ctrl-C and the request byte, which must reach the device unchanged
+    fs::write(local.join("data.bin"), binary_content(5000)).unwrap();
+    fs::write(
+        local.join("main.py"),
+        "import os, helper\n\
+         print(os.getcwd(), os.listdir())\n\
+         print(helper.greet('device'))\n\
+         data = open('data.bin', 'rb').read()\n\
+         print(len(data), sum(data))\n\
+         with open('lib/out.txt', 'w') as f:\n    f.write('from the device')\n\
+         print(os.stat('lib')[0] & 0x4000)\n",
+    )
+    .unwrap();
+    let port = sim_port("repl", &root);
+
+    let out = upyremote(&port, &["mount", local.to_str().unwrap(), "main.py"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    let expected_sum: usize = binary_content(5000).iter().map(|&b| b as usize).sum();
+    let output: String = stdout(&out)
+        .lines()
+        .filter(|line| !line.starts_with("[INFO]"))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    assert_eq!(
+        output,
+        format!(
+            "/remote ['data.bin', 'helper.py', 'lib', 'main.py']\n\
+             hello device\n\
+             5000 {}\n\
+             16384\n",
+            expected_sum
+        )
+    );
+    assert_eq!(
+        fs::read_to_string(local.join("lib/out.txt")).unwrap(),
+        "from the device"
+    );
+    // Nothing was copied to the device
+    assert!(fs::read_dir(&root).unwrap().next().is_none());
+
+    // Device-side errors come back as exceptions from the script
+    fs::write(local.join("bad.py"), "open('../../etc/passwd')\n").unwrap();
+    let out = upyremote(&port, &["mount", local.to_str().unwrap(), "bad.py"]);
+    assert!(!out.status.success());
+    assert!(stderr(&out).contains("EACCES"), "{}", stderr(&out));
+}
+
+#[test]
+fn mount_is_undone_when_the_script_fails() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("mount-timeout");
+    let local = root.with_extension("local");
+    let _ = fs::remove_dir_all(&local);
+    fs::create_dir_all(&local).unwrap();
+    fs::write(
+        local.join("loop.py"),
+        "import time\nwhile True:\n    time.sleep(0.1)\n",
+    )
+    .unwrap();
+    let script = root.with_extension("upy");
+    fs::write(
+        &script,
+        format!(
+            "on-error continue\n\
+             mount '{}' loop.py -t 1\n\
+             on-error abort\n\
+             exec \"import os; print('cwd', os.getcwd()); print(chr(24) + 'after')\"\n",
+            local.display()
+        ),
+    )
+    .unwrap();
+
+    let out = upyremote(
+        &sim_port("repl", &root),
+        &["script", script.to_str().unwrap()],
+    );
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert!(stderr(&out).contains("Timeout"), "{}", stderr(&out));
+    // The request byte is plain output again once the mount is gone
+    let output = stdout(&out);
+    assert!(output.contains("cwd /\n\x18after\n"), "{:?}", output);
+}
+
+#[test]
+fn watch_uploads_changes_and_reruns_entry_point() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("watch");
+    let local = root.with_extension("local");
+    let _ = fs::remove_dir_all(&local);
+    fs::create_dir_all(&local).unwrap();
+    fs::write(local.join("helper.py"), "X = 1\n").unwrap();
+    fs::write(
+        local.join("main.py"),
+        "import helper\nprint('run', helper.X)\n",
+    )
+    .unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_upyremote"))
+        .args(["watch", local.to_str().unwrap(), "/app", "--run", "main.py"])
+        .args(["-p", &sim_port("repl", &root)])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to run upyremote");
+    let lines = mpsc::channel();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    thread::spawn(move || {
+        stdout
+            .lines()
+            .map_while(Result::ok)
+            .try_for_each(|l| lines.0.send(l))
+    });
+    let wait_for = |text: &str| {
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while let Ok(line) = lines.1.recv_timeout(deadline - Instant::now()) {
+            if line == text {
+                return;
+            }
+        }
+        panic!("no '{}' from watch", text);
+    };
+
+    wait_for("run 1");
+    // A changed module is imported again after the soft reset
+    fs::write(local.join("helper.py"), "X = 22\n").unwrap();
+    wait_for("run 22");
+    // New directories are created on the device
+    fs::create_dir_all(local.join("lib")).unwrap();
+    fs::write(local.join("lib/data.txt"), "data").unwrap();
+    wait_for("run 22");
+    assert_eq!(
+        fs::read_to_string(root.join("app/lib/data.txt")).unwrap(),
+        "data"
+    );
+    // Code that still runs is interrupted by the next change
+    fs::write(
+        local.join("main.py"),
+        "import time\nprint('looping')\nwhile True:\n    time.sleep(0.1)\n",
+    )
+    .unwrap();
+    wait_for("looping");
+    fs::write(local.join("main.py"), "print('done')\n").unwrap();
+    wait_for("done");
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+#[test]
+fn ls_lists_repl_files() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("ls-repl");
+    fs::write(root.join("main.py"), "").unwrap();
+    fs::create_dir(root.join("lib")).unwrap();
+
+    let out = upyremote(&sim_port("repl", &root), &["ls", "/"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    let listing = stdout(&out);
+    assert!(listing.contains("  main.py\n"));
+    // Plain `ls` shows bare names; only -l and -R mark directories
+    assert!(listing.contains("  lib\n"), "{}", listing);
+    assert!(!listing.contains("lib/"), "{}", listing);
+}
+
+#[test]
+fn ls_long_recursive_and_tree() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("ls-long");
+    fs::create_dir_all(root.join("lib/sub")).unwrap();
+    fs::write(root.join("main.py"), "print(1)\n").unwrap();
+    fs::write(root.join("lib/sub/util.py"), binary_content(1_234)).unwrap();
+
+    for mode in ["repl", "upyos"] {
+        let port = sim_port(mode, &root);
+        let out = upyremote(&port, &["ls", "-l", "/"]);
+        assert!(out.status.success(), "{}", stderr(&out));
+        let listing = stdout(&out);
+        assert!(listing.contains("d         -"), "{}", listing);
+        assert!(listing.contains("  lib/"), "{}", listing);
+        assert!(listing.contains("         9  "), "{}", listing);
+        assert!(!listing.contains("util.py"), "{}", listing);
+
+        let out = upyremote(&port, &["ls", "-lR", ":/lib"]);
+        assert!(out.status.success(), "{}", stderr(&out));
+        assert!(stdout(&out).contains("      1234  "));
+        assert!(stdout(&out).contains("  sub/util.py"));
+
+        let out = upyremote(&port, &["tree", "/"]);
+        assert!(out.status.success(), "{}", stderr(&out));
+        let tree: Vec<String> = stdout(&out)
+            .lines()
+            .skip_while(|l| *l != "/")
+            .map(str::to_string)
+            .collect();
+        assert_eq!(
+            tree,
+            [
+                "/",
+                "├── lib",
+                "│   └── sub",
+                "│       └── util.py",
+                "└── main.py",
+                "",
+                "2 directories, 2 files",
+            ]
+        );
+    }
+}
+
+#[test]
+fn filesystem_commands() {
+    if python3_missing() {
+        return;
+    }
+    for mode in ["repl", "upyos"] {
+        let root = device_root(&format!("fs-{}", mode));
+        fs::write(root.join("main.py"), "print(1)\n").unwrap();
+        let port = sim_port(mode, &root);
+        let ok = |args: &[&str]| {
+            let out = upyremote(&port, args);
+            assert!(out.status.success(), "{:?}: {}", args, stderr(&out));
+            stdout(&out)
+        };
+        let fails = |args: &[&str], message: &str| {
+            let out = upyremote(&port, args);
+            assert!(!out.status.success(), "{:?} succeeded", args);
+            assert!(
+                stderr(&out).contains(message),
+                "{:?}: {}",
+                args,
+                stderr(&out)
+            );
+        };
+
+        fails(&["mkdir", "/a/b"], "No such directory: /a");
+        ok(&["mkdir", "-p", "/a/b"]);
+        assert!(root.join("a/b").is_dir());
+        fails(&["mkdir", "/a"], "'/a' already exists");
+
+        ok(&["cp", "/main.py", ":/a/b"]);
+        ok(&["mv", "/a/b/main.py", "/a/copy.py"]);
+        ok(&["touch", "/a/empty.txt"]);
+        assert_eq!(
+            fs::read_to_string(root.join("a/copy.py")).unwrap(),
+            "print(1)\n"
+        );
+        assert_eq!(fs::read(root.join("a/empty.txt")).unwrap(), b"");
+        assert!(ok(&["cat", "/a/copy.py"]).ends_with("print(1)\n"));
+
+        fails(&["cat", "/a"], "'/a' is a directory");
+        fails(&["cat", "/nope"], "No such file or directory: /nope");
+        fails(&["cp", "/a", "/b"], "'/a' is a directory");
+        fails(&["rm", "/a"], "'/a' is a directory (use -r)");
+        fails(&["rmdir", "/a"], "Directory not empty: /a");
+        fails(&["rmdir", "/main.py"], "'/main.py' is not a directory");
+
+        ok(&["rmdir", "/a/b"]);
+        ok(&["rm", "/a/empty.txt"]);
+        ok(&["rm", "-r", "/a"]);
+        assert!(!root.join("a").exists());
+        assert!(root.join("main.py").exists());
+    }
+}
+
+#[test]
+fn hostile_file_names_are_passed_as_data() {
+    if python3_missing() {
+        return;
+    }
+    for mode in ["repl", "upyos"] {
+        let root = device_root(&format!("names-{}", mode));
+        let local = root.with_extension("src");
+        fs::write(&local, "data\n").unwrap();
+        let port = sim_port(mode, &root);
+        let ok = |args: &[&str]| {
+            let out = upyremote(&port, args);
+            assert!(out.status.success(), "{:?}: {}", args, stderr(&out));
+            stdout(&out)
+        };
+
+        for name in [
+            "it's \"odd\" \\ name.py",
+            "x'); int('boom'); ('.py",
+            "tab\there.txt",
+        ] {
+            let remote = format!("/{}", name);
+            ok(&["put", local.to_str().unwrap(), &remote]);
+            assert_eq!(fs::read_to_string(root.join(name)).unwrap(), "data\n");
+            assert!(ok(&["ls", "/"]).contains(name));
+            assert!(ok(&["cat", &remote]).ends_with("data\n"));
+
+            let back = root.with_extension("back");
+            ok(&["get", &remote, back.to_str().unwrap()]);
+            assert_eq!(fs::read_to_string(&back).unwrap(), "data\n");
+
+            let moved = format!("{} moved", remote);
+            ok(&["mv", &remote, &moved]);
+            ok(&["rm", &moved]);
+            assert!(fs::read_dir(&root).unwrap().next().is_none(), "{}", name);
+        }
+    }
+}
+
+#[test]
+fn df_reports_filesystems() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("df");
+    let out = upyremote(&sim_port("repl", &root), &["df"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    let table = stdout(&out);
+    let mut lines = table.lines().skip_while(|line| line.starts_with("[INFO]"));
+    assert!(lines.next().unwrap().starts_with("Mounted on"), "{}", table);
+    assert!(lines.next().unwrap().starts_with("/ "), "{}", table);
+
+    // upyOS's own df command, whose table is 1K blocks
+    fs::write(root.join("data.bin"), binary_content(10_000)).unwrap();
+    // The JSON follows the status messages on a line of its own
+    let out = upyremote(&sim_port("upyos", &root), &["df", "--json"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    let output = stdout(&out);
+    let json = output.lines().last().unwrap_or_default();
+    assert!(
+        json.starts_with("[{\"mount\": \"/\", \"block_size\": "),
+        "{}",
+        json
+    );
+    for key in [
+        "blocks_used",
+        "blocks_free",
+        "bytes_total",
+        "bytes_used",
+        "bytes_free",
+        "bytes_avail",
+    ] {
+        assert!(json.contains(&format!("\"{}\": ", key)), "{}", json);
+    }
+    assert!(json.ends_with("}]"), "{}", json);
+    let number = |key: &str| -> u64 {
+        let start = json.find(&format!("\"{}\": ", key)).unwrap() + key.len() + 4;
+        let end = start + json[start..].find([',', '}']).unwrap();
+        json[start..end].parse().unwrap()
+    };
+    assert_eq!(
+        number("blocks_used") + number("blocks_free"),
+        number("blocks_total")
+    );
+    assert_eq!(number("block_size"), 1024);
+    assert_eq!(number("blocks_total"), 2048);
+    assert_eq!(number("blocks_used"), 10);
+}
+
+#[test]
+fn ls_missing_directory_fails() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("ls-missing");
+    let out = upyremote(&sim_port("repl", &root), &["ls", "/nope"]);
+    assert!(!out.status.success());
+    assert!(stderr(&out).contains("OSError"));
+}
+
+#[test]
+fn put_and_get_binary_with_raw_paste() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("transfer-raw-paste");
+    let local = root.with_extension("bin");
+    let content = binary_content(3000);
+    fs::write(&local, &content).unwrap();
+    let port = sim_port("repl", &root);
+
+    let out = upyremote(&port, &["put", local.to_str().unwrap(), "/data.bin"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert_eq!(fs::read(root.join("data.bin")).unwrap(), content);
+
+    let back = root.with_extension("back");
+    let out = upyremote(&port, &["get", "/data.bin", back.to_str().unwrap()]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert_eq!(fs::read(&back).unwrap(), content);
+}
+
+#[test]
+fn put_and_get_binary_without_raw_paste() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("transfer-legacy");
+    let local = root.with_extension("bin");
+    let content = binary_content(1000);
+    fs::write(&local, &content).unwrap();
+    let port = sim_port("repl-legacy", &root);
+
+    let out = upyremote(&port, &["put", local.to_str().unwrap(), "/data.bin"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert_eq!(fs::read(root.join("data.bin")).unwrap(), content);
+
+    let back = root.with_extension("back");
+    let out = upyremote(&port, &["get", "/data.bin", back.to_str().unwrap()]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert_eq!(fs::read(&back).unwrap(), content);
+}
+
+#[test]
+fn get_missing_file_fails() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("get-missing");
+    let back = root.with_extension("back");
+    let out = upyremote(
+        &sim_port("repl", &root),
+        &["get", "/missing.txt", back.to_str().unwrap()],
+    );
+    assert!(!out.status.success());
+    assert!(stderr(&out).contains("missing.txt"));
+    assert!(!back.exists());
+}
+
+#[test]
+fn upyos_put_ls_and_get_text() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("upyos-transfer");
+    let local = root.with_extension("txt");
+    fs::write(&local, "first line\nsecond line\n").unwrap();
+    let port = sim_port("upyos", &root);
+
+    let out = upyremote(&port, &["put", local.to_str().unwrap(), "/notes.txt"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert_eq!(
+        fs::read_to_string(root.join("notes.txt")).unwrap(),
+        "first line\nsecond line\n"
+    );
+
+    let out = upyremote(&port, &["ls", "/"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    let listing: Vec<String> = stdout(&out)
+        .lines()
+        .skip_while(|l| !l.starts_with("Files in"))
+        .skip(1)
+        .map(|l| l.trim().to_string())
+        .collect();
+    assert_eq!(listing, ["notes.txt"]);
+
+    let back = root.with_extension("back");
+    let out = upyremote(&port, &["get", "/notes.txt", back.to_str().unwrap()]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert_eq!(
+        fs::read_to_string(&back).unwrap(),
+        "first line\nsecond line\n"
+    );
+}
+
+#[test]
+fn upyos_put_and_get_binary() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("upyos-binary");
+    let local = root.with_extension("bin");
+    // Larger than one fileup part, with CR, LF and "$:" in the data
+    let mut content = binary_content(25_000);
+    content.extend_from_slice(b"\r\n/ $: \n");
+    fs::write(&local, &content).unwrap();
+    let port = sim_port("upyos", &root);
+
+    let out = upyremote(&port, &["put", local.to_str().unwrap(), "/data.bin"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert_eq!(fs::read(root.join("data.bin")).unwrap(), content);
+
+    let back = root.with_extension("back");
+    let out = upyremote(&port, &["get", "/data.bin", back.to_str().unwrap()]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert_eq!(fs::read(&back).unwrap(), content);
+
+    // Helper files are cleaned up
+    let names: Vec<_> = fs::read_dir(&root)
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    assert_eq!(names, ["data.bin"]);
+}
+
+#[test]
+fn send_runs_upyos_command() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("send-upyos");
+    let out = upyremote(&sim_port("upyos", &root), &["send", "echo hello"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert!(stdout(&out).contains("hello"));
+}
+
+#[test]
+fn chained_commands_share_one_connection() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("chain");
+    let local = root.with_extension("py");
+    fs::write(&local, "print('chained')\n").unwrap();
+    // upyremote() adds -p to the last command; the first needs it too
+    let port = sim_port("repl", &root);
+    let local = local.to_str().unwrap();
+
+    let out = upyremote(
+        &port,
+        &[
+            "put", local, "/a.py", "-p", &port, "+", "cp", "/a.py", "/b.py", "+", "exec",
+            "import b", "+", "ls",
+        ],
+    );
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert_eq!(stdout(&out).matches("Detected mode").count(), 1);
+    let output = stdout(&out);
+    assert!(output.contains("chained\n"), "{}", output);
+    assert!(output.contains("  a.py\n  b.py"), "{}", output);
+
+    // The whole chain is parsed before any of it runs
+    let out = upyremote(&port, &["rm", "/a.py", "-p", &port, "+", "put"]);
+    assert!(!out.status.success());
+    assert!(root.join("a.py").exists());
+
+    // A doubled `+` is an argument, not a separator
+    let out = upyremote(&port, &["mkdir", "++", "--port", &port, "+", "ls"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert!(root.join("+").is_dir());
+
+    // A failing command stops the chain
+    let out = upyremote(&port, &["rm", "/nope", "-p", &port, "+", "rm", "/a.py"]);
+    assert!(!out.status.success());
+    assert!(root.join("a.py").exists());
+}
+
+#[test]
+fn script_runs_commands_with_variables() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("script");
+    let local = root.with_extension("py");
+    fs::write(&local, "print('from script')\n").unwrap();
+    let script = root.with_extension("upy");
+    fs::write(
+        &script,
+        format!(
+            "# Deploy\n\
+             set SRC '{}'\n\
+             set DEST /app.py  # overridden with -s\n\
+             \n\
+             put ${{SRC}} \"${{DEST}}\" -p ${{SCRIPT_PORT}}\n\
+             on-error continue\n\
+             rm /missing\n\
+             exec 'raise ValueError(\"x\")'\n\
+             on-error abort\n\
+             exec \"import main\"\n\
+             rm /missing\n\
+             ls\n",
+            local.display()
+        ),
+    )
+    .unwrap();
+
+    let out = Command::new(env!("CARGO_BIN_EXE_upyremote"))
+        .args(["script", script.to_str().unwrap(), "-s", "DEST=/main.py"])
+        .env("SCRIPT_PORT", sim_port("repl", &root))
+        .env("RUST_BACKTRACE", "0")
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    let (output, errors) = (stdout(&out), stderr(&out));
+    assert_eq!(output.matches("Detected mode").count(), 1);
+    assert!(root.join("main.py").exists());
+    assert!(output.contains("from script"), "{}", output);
+    assert!(
+        errors.contains(":7: rm /missing failed, continuing"),
+        "{}",
+        errors
+    );
+    assert!(errors.contains("ValueError: x"), "{}", errors);
+    assert!(errors.contains(":11: rm /missing failed"), "{}", errors);
+    // Nothing runs after an aborting failure
+    assert!(!output.contains("Files in"), "{}", output);
+
+    fs::write(&script, "ls\nput ${UNSET_VARIABLE}\n").unwrap();
+    let out = upyremote(
+        &sim_port("repl", &root),
+        &["script", script.to_str().unwrap()],
+    );
+    assert!(!out.status.success());
+    assert!(stderr(&out).contains("Variable 'UNSET_VARIABLE' is not set"));
+    assert!(!stdout(&out).contains("Files in"));
+}
+
+#[test]
+fn port_alias_from_local_config() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("alias");
+    let project = root.with_extension("project");
+    fs::create_dir_all(&project).unwrap();
+    fs::write(
+        project.join("upyremote.toml"),
+        format!("[aliases]\nbench = \"{}\"\n", sim_port("repl", &root)),
+    )
+    .unwrap();
+
+    let out = upyremote_in(&project, &["exec", "print('via alias')", "-p", "bench"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert!(stdout(&out).contains("via alias"));
+}
+
+#[test]
+fn profile_sets_port_and_chunk_size() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("profile");
+    let project = root.with_extension("project");
+    fs::create_dir_all(&project).unwrap();
+    fs::write(
+        project.join("upyremote.toml"),
+        format!(
+            "[profiles.legacy]\nport = \"{}\"\nchunk_size = 64\n",
+            sim_port("repl-legacy", &root)
+        ),
+    )
+    .unwrap();
+    let content = binary_content(500);
+    fs::write(project.join("data.bin"), &content).unwrap();
+
+    let out = upyremote_in(&project, &["--profile", "legacy", "put", "data.bin"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert_eq!(fs::read(root.join("data.bin")).unwrap(), content);
+
+    let out = upyremote_in(&project, &["ls", "--profile", "missing"]);
+    assert!(!out.status.success());
+    assert!(stderr(&out).contains("available: legacy"));
+}
+
+#[test]
+fn baud_option_is_global() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("baud");
+    let port = sim_port("repl", &root);
+
+    let out = upyremote(&port, &["ls", "--baud", "auto", "/"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+
+    let out = upyremote(&port, &["--baud", "9600", "exec", "print(1)"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+
+    let out = upyremote(&port, &["ls", "--baud", "fast"]);
+    assert!(!out.status.success());
+    assert!(stderr(&out).contains("invalid baud rate"));
+}
+
+#[test]
+fn put_large_file_in_chunks() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("put-chunks");
+    let local = root.with_extension("bin");
+    let content = binary_content(40_000);
+    fs::write(&local, &content).unwrap();
+
+    let out = upyremote(
+        &sim_port("repl", &root),
+        &[
+            "put",
+            "--chunk-size",
+            "700",
+            local.to_str().unwrap(),
+            "/big.bin",
+        ],
+    );
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert!(stdout(&out).contains("(40000 bytes)"));
+    assert_eq!(fs::read(root.join("big.bin")).unwrap(), content);
+}
+
+#[test]
+fn progress_is_shown_only_when_requested() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("progress");
+    let local = root.with_extension("bin");
+    fs::write(&local, binary_content(5_000)).unwrap();
+    let port = sim_port("repl", &root);
+
+    // stdout is a pipe here, so progress is off by default
+    let out = upyremote(&port, &["put", local.to_str().unwrap(), "/a.bin"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert!(!stdout(&out).contains('%'));
+
+    let back = root.with_extension("back");
+    let out = upyremote(
+        &port,
+        &["--progress", "get", "/a.bin", back.to_str().unwrap()],
+    );
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert!(stdout(&out).contains("Downloading /a.bin"));
+    assert!(stdout(&out).contains("100% 4.9 KB/4.9 KB"));
+
+    let out = upyremote(&port, &["-q", "--progress", "get", "/a.bin"]);
+    assert!(!out.status.success());
+}
+
+#[test]
+fn put_recursive_with_filters() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("put-tree");
+    let local = root.with_extension("src");
+    let _ = fs::remove_dir_all(&local);
+    for dir in ["lib/sub", "__pycache__", "tests", "empty"] {
+        fs::create_dir_all(local.join(dir)).unwrap();
+    }
+    fs::write(local.join("main.py"), "print('main')\n").unwrap();
+    fs::write(local.join("lib/data.bin"), binary_content(3_000)).unwrap();
+    fs::write(local.join("lib/sub/util.py"), "X = 1\n").unwrap();
+    fs::write(local.join("__pycache__/main.pyc"), "junk").unwrap();
+    fs::write(local.join("tests/test_main.py"), "").unwrap();
+    fs::write(local.join("notes.txt"), "").unwrap();
+    let port = sim_port("repl", &root);
+
+    let out = upyremote(&port, &["put", local.to_str().unwrap()]);
+    assert!(!out.status.success());
+    assert!(stderr(&out).contains("use -r"));
+
+    let out = upyremote(
+        &port,
+        &[
+            "put",
+            "-r",
+            local.to_str().unwrap(),
+            ":/apps/demo",
+            "--exclude",
+            "__pycache__",
+            "--exclude",
+            "tests",
+            "--exclude",
+            "*.txt",
+        ],
+    );
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert!(stdout(&out).contains("3 files uploaded"));
+    let dest = root.join("apps/demo");
+    assert_eq!(
+        fs::read(dest.join("lib/data.bin")).unwrap(),
+        binary_content(3_000)
+    );
+    assert!(dest.join("lib/sub/util.py").is_file());
+    assert!(dest.join("main.py").is_file());
+    assert!(dest.join("empty").is_dir());
+    assert!(!dest.join("__pycache__").exists());
+    assert!(!dest.join("tests").exists());
+    assert!(!dest.join("notes.txt").exists());
+
+    let out = upyremote(
+        &port,
+        &[
+            "put",
+            "-r",
+            local.to_str().unwrap(),
+            ":/py",
+            "--include",
+            "lib/**/*.py",
+        ],
+    );
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert!(root.join("py/lib/sub/util.py").is_file());
+    assert!(!root.join("py/main.py").exists());
+    assert!(!root.join("py/empty").exists());
+}
+
+#[test]
+fn put_into_missing_directory_fails() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("put-missing-dir");
+    let local = root.with_extension("txt");
+    fs::write(&local, "data").unwrap();
+
+    let out = upyremote(
+        &sim_port("repl", &root),
+        &["put", local.to_str().unwrap(), "/nope/file.txt"],
+    );
+    assert!(!out.status.success());
+    assert!(stderr(&out).contains("ENOENT"));
+}
+
+#[test]
+fn get_recursive_backup() {
+    if python3_missing() {
+        return;
+    }
+    for mode in ["repl", "upyos"] {
+        let root = device_root(&format!("get-tree-{}", mode));
+        fs::create_dir_all(root.join("logs/2024")).unwrap();
+        fs::create_dir_all(root.join("empty")).unwrap();
+        fs::write(root.join("main.py"), "print('main')\n").unwrap();
+        fs::write(root.join("logs/2024/data.bin"), binary_content(2_000)).unwrap();
+        fs::write(root.join("logs/boot.log"), "booted\r\n").unwrap();
+        let backup = root.with_extension("backup");
+        let _ = fs::remove_dir_all(&backup);
+
+        let out = upyremote(
+            &sim_port(mode, &root),
+            &["get", "-r", ":/", backup.to_str().unwrap()],
+        );
+        assert!(out.status.success(), "{}", stderr(&out));
+        assert!(
+            stdout(&out).contains("3 files downloaded"),
+            "{}",
+            stdout(&out)
+        );
+        assert_eq!(
+            fs::read(backup.join("logs/2024/data.bin")).unwrap(),
+            binary_content(2_000)
+        );
+        assert_eq!(
+            fs::read_to_string(backup.join("logs/boot.log")).unwrap(),
+            "booted\r\n"
+        );
+        assert!(backup.join("main.py").is_file());
+        assert!(backup.join("empty").is_dir());
+        // Nothing of the upyOS helper ends up in the backup
+        assert_eq!(fs::read_dir(&backup).unwrap().count(), 3);
+    }
+}
+
+#[test]
+fn sync_uploads_changes_and_deletes() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("sync");
+    let local = root.with_extension("src");
+    let _ = fs::remove_dir_all(&local);
+    fs::create_dir_all(local.join("lib")).unwrap();
+    fs::write(local.join("main.py"), "print(1)\n").unwrap();
+    fs::write(local.join("lib/util.py"), "X = 1\n").unwrap();
+    let port = sim_port("repl", &root);
+    let sync = |extra: &[&str]| {
+        let mut args = vec!["sync", local.to_str().unwrap(), ":/app"];
+        args.extend_from_slice(extra);
+        let out = upyremote(&port, &args);
+        assert!(out.status.success(), "{}", stderr(&out));
+        stdout(&out)
+    };
+
+    assert!(sync(&[]).contains("2 uploaded, 0 deleted, 0 unchanged"));
+    assert!(sync(&[]).contains("0 uploaded, 0 deleted, 2 unchanged"));
+
+    // Same size, different content: caught by the hash
+    fs::write(local.join("main.py"), "print(2)\n").unwrap();
+    fs::write(root.join("app/stale.py"), "").unwrap();
+    let plan = sync(&["--delete", "--dry-run"]);
+    assert!(plan.contains("upload  /app/main.py (changed)"), "{}", plan);
+    assert!(plan.contains("delete  /app/stale.py"), "{}", plan);
+    assert!(root.join("app/stale.py").exists());
+
+    let out = sync(&["--delete"]);
+    assert!(
+        out.contains("1 uploaded, 1 deleted, 1 unchanged"),
+        "{}",
+        out
+    );
+    assert_eq!(
+        fs::read_to_string(root.join("app/main.py")).unwrap(),
+        "print(2)\n"
+    );
+    assert!(!root.join("app/stale.py").exists());
+
+    // Only the missing part of a new target directory is created
+    let out = upyremote(
+        &port,
+        &[
+            "sync",
+            local.to_str().unwrap(),
+            ":/app/new/deep",
+            "--dry-run",
+        ],
+    );
+    let plan = stdout(&out);
+    assert!(plan.contains("mkdir   /app/new\n"), "{}", plan);
+    assert!(plan.contains("mkdir   /app/new/deep\n"), "{}", plan);
+    assert!(!plan.contains("mkdir   /app\n"), "{}", plan);
+}
+
+#[test]
+fn sync_delete_keeps_excluded_directories() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("sync-exclude");
+    let local = root.with_extension("src");
+    let _ = fs::remove_dir_all(&local);
+    fs::create_dir_all(local.join("lib")).unwrap();
+    fs::write(local.join("lib/util.py"), "X = 1\n").unwrap();
+    fs::create_dir_all(root.join("lib/__pycache__/deep")).unwrap();
+    fs::write(root.join("lib/__pycache__/x.mpy"), "mpy").unwrap();
+    fs::write(root.join("lib/__pycache__/deep/y.mpy"), "mpy").unwrap();
+    fs::write(root.join("lib/stale.py"), "").unwrap();
+    let port = sim_port("repl", &root);
+    let sync = |extra: &[&str]| {
+        let mut args = vec![
+            "sync",
+            local.to_str().unwrap(),
+            ":/",
+            "--delete",
+            "--exclude",
+            "__pycache__",
+        ];
+        args.extend_from_slice(extra);
+        let out = upyremote(&port, &args);
+        assert!(out.status.success(), "{}", stderr(&out));
+        stdout(&out)
+    };
+
+    let plan = sync(&["-n"]);
+    assert!(plan.contains("delete  /lib/stale.py"), "{}", plan);
+    assert!(!plan.contains("__pycache__"), "{}", plan);
+
+    let out = sync(&[]);
+    assert!(out.contains("1 deleted"), "{}", out);
+    assert!(!root.join("lib/stale.py").exists());
+    assert!(root.join("lib/__pycache__/x.mpy").exists());
+    assert!(root.join("lib/__pycache__/deep/y.mpy").exists());
+}
+
+#[test]
+fn get_large_file_in_chunks() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("get-chunks");
+    let content = binary_content(40_000);
+    fs::write(root.join("big.bin"), &content).unwrap();
+
+    let back = root.with_extension("back");
+    let out = upyremote(
+        &sim_port("repl", &root),
+        &[
+            "get",
+            "--chunk-size",
+            "700",
+            "/big.bin",
+            back.to_str().unwrap(),
+        ],
+    );
+    assert!(out.status.success(), "{}", stderr(&out));
+    // The hash was checked, so there is no size-only notice on either stream
+    for output in [stdout(&out), stderr(&out)] {
+        assert!(!output.contains("only the size was verified"), "{}", output);
+    }
+    assert_eq!(fs::read(&back).unwrap(), content);
+}
+
+#[test]
+fn get_verifies_hash_at_padding_boundaries() {
+    if python3_missing() {
+        return;
+    }
+    let root = device_root("get-hash");
+    let port = sim_port("repl", &root);
+    for len in [0, 55, 56, 63, 64, 65] {
+        let content = binary_content(len);
+        fs::write(root.join("f.bin"), &content).unwrap();
+
+        let back = root.with_extension(format!("back{}", len));
+        let out = upyremote(&port, &["get", "/f.bin", back.to_str().unwrap()]);
+        assert!(out.status.success(), "{} bytes: {}", len, stderr(&out));
+        assert_eq!(fs::read(&back).unwrap(), content);
+    }
+}